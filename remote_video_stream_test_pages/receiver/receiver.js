@@ -33,6 +33,9 @@ const handleSignallingData = (data) => {
         case 'candidate':
             peerConn.addIceCandidate(data);
             console.log('data.candidate: ', data.candidate);
+            break;
+        case 'error':
+            console.error(`[${data.code}] ${data.message}`, data.request_id);
     };
 };
 
@@ -64,34 +67,12 @@ const joinCall = () => {
             if (e.candidate == null) return
             sendRoomData({
                 data_type: 'send_candidate',
-                offer: {
-                    type: '',
-                    sdp: ''
-                },
-                answer: {
-                    type: '',
-                    sdp: ''
-                },
                 candidate: e.candidate
             });
         });
 
         sendRoomData({
-            data_type: 'join_call',
-            offer: {
-                type: '',
-                sdp: ''
-            },
-            answer: {
-                type: '',
-                sdp: ''
-            },
-            candidate: {
-                candidate: '', 
-                sdpMid: '', 
-                sdpMLineIndex: 0, 
-                usernameFragment: '',
-            }
+            data_type: 'join_call'
         });
     }).catch((err) => {
         console.log(err);
@@ -103,17 +84,7 @@ const createAndSendAnswer = () => {
         peerConn.setLocalDescription(answer);
         sendRoomData({
             data_type: 'send_answer',
-            offer: {
-                type: '',
-                sdp: ''
-            },
-            answer: answer,
-            candidate: {
-                candidate: '', 
-                sdpMid: '', 
-                sdpMLineIndex: 0, 
-                usernameFragment: '',
-            }
+            answer: answer
         });
     }).catch((err) => {
         console.log(err);
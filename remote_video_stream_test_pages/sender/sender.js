@@ -34,27 +34,16 @@ const handleSignallingData = (data) => {
             peerConn.addIceCandidate(data.candidate);
             console.log('data.candidate: ', data.candidate);
             webSocket.onerror = console.log;
+            break;
+        case 'error':
+            console.error(`[${data.code}] ${data.message}`, data.request_id);
     };
 };
 
 const sendRoomId = () => {
     roomId = document.getElementById('room-id-input').value;
     sendRoomData({
-        data_type: 'store_room',
-        offer: {
-            type: '',
-            sdp: ''
-        },
-        answer: {
-            type: '',
-            sdp: ''
-        },
-        candidate: {
-            candidate: '', 
-            sdpMid: '', 
-            sdpMLineIndex: 0, 
-            usernameFragment: '',
-        }
+        data_type: 'store_room'
     });
 };
 
@@ -88,14 +77,6 @@ const startCall = () => {
             if (e.candidate == null) return
             sendRoomData({
                 data_type: 'store_candidate',
-                offer: {
-                    type: '',
-                    sdp: ''
-                },
-                answer: {
-                    type: '',
-                    sdp: ''
-                },
                 candidate: e.candidate
             });
         });
@@ -109,17 +90,7 @@ const createAndSendOffer = () => {
     peerConn.createOffer().then((offer) => {
         sendRoomData({
             data_type: 'store_offer',
-            offer: offer,
-            answer: {
-                type: '',
-                sdp: ''
-            },
-            candidate: {
-                candidate: '', 
-                sdpMid: '', 
-                sdpMLineIndex: 0, 
-                usernameFragment: '',
-            }
+            offer: offer
         });
         peerConn.setLocalDescription(offer);
     }).catch((err) => {
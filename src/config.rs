@@ -0,0 +1,471 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration
+};
+
+use crate::websocket::data_transfer::RoomPolicy;
+
+/// Prefix of the environment variables read by [`Settings::load`], e.g. `WS_SERVER_PORT`.
+pub const ENV_PREFIX: &str = "WS_SERVER_";
+
+pub const USAGE: &str = "\
+Usage: rust_websocket_server [OPTIONS]
+
+Options:
+  --config <PATH>            TOML file to read settings from
+  --host <IP>                Address to listen on [default: 0.0.0.0]
+  --port <PORT>              Port to listen on [default: 7878]
+  --ws-path <PATH>           Path clients upgrade on [default: /ws]
+  --http-path <PATH>         Prefix of the SSE and long-poll fallback [default: /http]
+  --http-session-timeout <SECONDS>
+                             Idle time before a fallback session is closed [default: 60]
+  --metrics-path <PATH>      Path Prometheus scrapes [default: /metrics]
+  --admin-path <PATH>        Prefix of the admin API [default: /admin]
+  --admin-token <TOKEN>      Bearer token required by the admin API, which is off without one
+  --max-connections <N>      Concurrent WebSocket connections [default: 10000]
+  --max-message-size <BYTES> Largest inbound message accepted [default: 65536]
+  --ping-interval <SECONDS>  Time between pings sent to WebSocket peers [default: 20]
+  --pong-timeout <SECONDS>   Time a peer has to answer a ping before it is dropped [default: 10]
+  --idle-timeout <SECONDS>   Drop WebSocket peers that send no message for this long [default: never]
+  --resume-window <SECONDS>  Time a dropped WebSocket peer has to reconnect and resume, 0 disables [default: 30]
+  --room-policy <POLICY>     destroy, transfer_ownership or keep_until_empty [default: destroy]
+  --log-level <LEVEL>        error, warn, info, debug or trace [default: info]
+  --log-format <FORMAT>      text or json [default: text]
+  --log-sdp <BOOL>           Log SDP and ICE candidates instead of redacting them [default: false]
+  --allowed-origins <LIST>   Comma-separated Origin values allowed to upgrade [default: any]
+  --drain-timeout <SECONDS>  Time given to open calls to finish on shutdown [default: 10]
+  --store-url <URL>          Room storage: memory, sqlite://PATH, mysql://... or redis://... [default: memory]
+  --bus-url <URL>            Bus to the other instances: memory or redis://..., needs a shared store [default: memory]
+  --jwt-secret <SECRET>      Require HS256 JWTs signed with this secret on upgrade
+  --jwt-issuer <ISS>         Required iss claim of those JWTs
+  --jwt-audience <AUD>       Required aud claim of those JWTs
+  --static-tokens <LIST>     Require one of these token:user_id pairs on upgrade, for testing
+  --tls-cert <PATH>          PEM certificate chain, serves wss:// together with --tls-key
+  --tls-key <PATH>           PEM private key of the certificate
+  -h, --help                 Print this help
+
+Every option can also be set through the environment (e.g. WS_SERVER_PORT=8080)
+or in the config file (e.g. port = 8080). Command line flags take precedence over
+the environment, which takes precedence over the config file.";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    Help,
+    UnknownOption(String),
+    MissingValue(String),
+    InvalidValue { key: String, value: String, reason: String },
+    File { path: String, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Help => write!(f, "{}", USAGE),
+            ConfigError::UnknownOption(key) => write!(f, "Unknown option: {}", key),
+            ConfigError::MissingValue(key) => write!(f, "Option {} requires a value", key),
+            ConfigError::InvalidValue { key, value, reason } => write!(f, "Invalid value {:?} for {}: {}", value, key, reason),
+            ConfigError::File { path, reason } => write!(f, "Failed to read config file {}: {}", path, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(String::from("expected one of error, warn, info, debug, trace")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(String::from("expected text or json")),
+        }
+    }
+}
+
+/// Runtime settings of the server, layered from defaults, an optional TOML file,
+/// `WS_SERVER_*` environment variables and command line flags.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub host: IpAddr,
+    pub port: u16,
+    pub ws_path: String,
+    /// Prefix of the HTTP fallback routes, see [`crate::websocket::fallback::HttpFallback`].
+    pub http_path: String,
+    /// How long a fallback session may go without a request before its peer is dropped.
+    pub http_session_timeout: Duration,
+    pub metrics_path: String,
+    /// Prefix of the admin routes, see [`crate::websocket::admin::AdminApi`].
+    pub admin_path: String,
+    /// The admin API answers `404 Not Found` to everything until this is set.
+    pub admin_token: Option<String>,
+    pub max_connections: usize,
+    pub max_message_size: usize,
+    /// How often WebSocket peers are pinged.
+    pub ping_interval: Duration,
+    /// How long a ping may go unanswered before the peer is dropped as gone.
+    pub pong_timeout: Duration,
+    /// Drops WebSocket peers that send no signaling message for this long. Off when unset.
+    pub idle_timeout: Option<Duration>,
+    /// How long a WebSocket peer whose connection dropped is kept for it to resume, see
+    /// [`crate::websocket::resume`]. Zero closes it at once.
+    pub resume_window: Duration,
+    pub room_policy: RoomPolicy,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    /// Log SDP and ICE candidate contents instead of only their length.
+    pub log_sdp: bool,
+    /// Origins browsers may open a socket from, e.g. `https://app.example.com`. Empty allows any.
+    pub allowed_origins: Vec<String>,
+    /// How long a shutdown waits for peers to hang up after the close frames went out.
+    pub drain_timeout: Duration,
+    /// Where room and peer state lives, see [`crate::websocket::store::open`]. In memory when unset.
+    pub store_url: Option<String>,
+    /// How this instance reaches the others, see [`crate::websocket::bus::open`]. Alone when unset.
+    pub bus_url: Option<String>,
+    pub jwt_secret: Option<String>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    /// Fixed tokens mapped to user ids, see [`crate::websocket::auth::StaticTokenAuthenticator`].
+    pub static_tokens: HashMap<String, String>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            host: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 7878,
+            ws_path: String::from("/ws"),
+            http_path: String::from("/http"),
+            http_session_timeout: Duration::from_secs(60),
+            metrics_path: String::from("/metrics"),
+            admin_path: String::from("/admin"),
+            admin_token: None,
+            max_connections: 10_000,
+            max_message_size: 64 << 10,
+            ping_interval: Duration::from_secs(20),
+            pong_timeout: Duration::from_secs(10),
+            idle_timeout: None,
+            resume_window: Duration::from_secs(30),
+            room_policy: RoomPolicy::default(),
+            log_level: LogLevel::Info,
+            log_format: LogFormat::Text,
+            log_sdp: false,
+            allowed_origins: Vec::new(),
+            drain_timeout: Duration::from_secs(10),
+            store_url: None,
+            bus_url: None,
+            jwt_secret: None,
+            jwt_issuer: None,
+            jwt_audience: None,
+            static_tokens: HashMap::new(),
+            tls_cert: None,
+            tls_key: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Reads the settings of the running process.
+    pub fn load() -> Result<Settings, ConfigError> {
+        Settings::from_sources(std::env::args().skip(1), |key| std::env::var(key).ok())
+    }
+
+    pub fn from_sources<I, E>(args: I, env: E) -> Result<Settings, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+        E: Fn(&str) -> Option<String>,
+    {
+        let mut cli_path: Option<String> = None;
+        let mut cli: Vec<(String, String)> = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                return Err(ConfigError::Help);
+            }
+            let flag: &str = arg.strip_prefix("--").ok_or_else(|| ConfigError::UnknownOption(arg.clone()))?;
+            let (name, value): (String, String) = match flag.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => (flag.to_string(), args.next().ok_or_else(|| ConfigError::MissingValue(arg.clone()))?),
+            };
+            let key: String = name.replace('-', "_");
+            if key == "config" {
+                cli_path = Some(value);
+            } else {
+                cli.push((key, value));
+            }
+        }
+
+        let mut settings: Settings = Settings::default();
+
+        if let Some(path) = cli_path.or_else(|| env(&format!("{}CONFIG", ENV_PREFIX))) {
+            let content: String = fs::read_to_string(&path)
+                .map_err(|e| ConfigError::File { path: path.clone(), reason: e.to_string() })?;
+            let table: toml::Table = content.parse()
+                .map_err(|e: toml::de::Error| ConfigError::File { path: path.clone(), reason: e.to_string() })?;
+            for (key, value) in table {
+                let value: String = match value {
+                    toml::Value::String(value) => value,
+                    toml::Value::Array(values) => values.iter()
+                        .map(|value| value.as_str().map(String::from).unwrap_or_else(|| value.to_string()))
+                        .collect::<Vec<String>>()
+                        .join(","),
+                    value => value.to_string(),
+                };
+                settings.set(&key, &value)?;
+            }
+        }
+
+        for key in Settings::KEYS {
+            if let Some(value) = env(&format!("{}{}", ENV_PREFIX, key.to_ascii_uppercase())) {
+                settings.set(key, &value)?;
+            }
+        }
+
+        for (key, value) in cli {
+            settings.set(&key, &value)?;
+        }
+
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+
+    const KEYS: [&'static str; 28] = [
+        "host", "port", "ws_path", "http_path", "http_session_timeout", "metrics_path", "admin_path", "admin_token",
+        "max_connections", "max_message_size", "ping_interval", "pong_timeout", "idle_timeout", "resume_window",
+        "room_policy", "log_level", "log_format", "log_sdp",
+        "allowed_origins", "drain_timeout", "store_url", "bus_url", "jwt_secret", "jwt_issuer", "jwt_audience",
+        "static_tokens", "tls_cert", "tls_key",
+    ];
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        match key {
+            "host" => self.host = parse(key, value)?,
+            "port" => self.port = parse(key, value)?,
+            "ws_path" => self.ws_path = value.to_string(),
+            "http_path" => self.http_path = value.trim_end_matches('/').to_string(),
+            "http_session_timeout" => self.http_session_timeout = Duration::from_secs(parse(key, value)?),
+            "metrics_path" => self.metrics_path = value.to_string(),
+            "admin_path" => self.admin_path = value.trim_end_matches('/').to_string(),
+            "admin_token" => self.admin_token = Some(value.to_string()),
+            "max_connections" => self.max_connections = parse(key, value)?,
+            "max_message_size" => self.max_message_size = parse(key, value)?,
+            "ping_interval" => self.ping_interval = Duration::from_secs(parse(key, value)?),
+            "pong_timeout" => self.pong_timeout = Duration::from_secs(parse(key, value)?),
+            "idle_timeout" => self.idle_timeout = Some(Duration::from_secs(parse(key, value)?)),
+            "resume_window" => self.resume_window = Duration::from_secs(parse(key, value)?),
+            "room_policy" => self.room_policy = parse(key, value)?,
+            "log_level" => self.log_level = parse(key, value)?,
+            "log_format" => self.log_format = parse(key, value)?,
+            "log_sdp" => self.log_sdp = parse(key, value)?,
+            "allowed_origins" => {
+                self.allowed_origins = value.split(',')
+                    .map(|origin| origin.trim().trim_end_matches('/').to_string())
+                    .filter(|origin| !origin.is_empty())
+                    .collect();
+            },
+            "drain_timeout" => self.drain_timeout = Duration::from_secs(parse(key, value)?),
+            "store_url" => self.store_url = Some(value.to_string()),
+            "bus_url" => self.bus_url = Some(value.to_string()),
+            "jwt_secret" => self.jwt_secret = Some(value.to_string()),
+            "jwt_issuer" => self.jwt_issuer = Some(value.to_string()),
+            "jwt_audience" => self.jwt_audience = Some(value.to_string()),
+            "static_tokens" => {
+                let mut tokens: HashMap<String, String> = HashMap::new();
+                for pair in value.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+                    match pair.split_once(':') {
+                        Some((token, user_id)) if !token.is_empty() && !user_id.is_empty() => {
+                            tokens.insert(token.to_string(), user_id.to_string());
+                        },
+                        _ => return Err(invalid(key, pair, "expected token:user_id")),
+                    }
+                }
+                self.static_tokens = tokens;
+            },
+            "tls_cert" => self.tls_cert = Some(PathBuf::from(value)),
+            "tls_key" => self.tls_key = Some(PathBuf::from(value)),
+            _ => return Err(ConfigError::UnknownOption(key.to_string())),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !self.ws_path.starts_with('/') {
+            return Err(invalid("ws_path", &self.ws_path, "must start with '/'"));
+        }
+        if !self.http_path.starts_with('/') || self.ws_path.starts_with(&self.http_path) {
+            return Err(invalid("http_path", &self.http_path, "must start with '/' and not overlap ws_path"));
+        }
+        if !self.metrics_path.starts_with('/') {
+            return Err(invalid("metrics_path", &self.metrics_path, "must start with '/'"));
+        }
+        if !self.admin_path.starts_with('/') || self.ws_path.starts_with(&self.admin_path) || self.admin_path == self.http_path {
+            return Err(invalid("admin_path", &self.admin_path, "must start with '/' and not overlap ws_path or http_path"));
+        }
+        if self.admin_token.as_deref() == Some("") {
+            return Err(invalid("admin_token", "", "must not be empty"));
+        }
+        if self.http_session_timeout.is_zero() {
+            return Err(invalid("http_session_timeout", "0", "must be greater than zero"));
+        }
+        if self.max_connections == 0 {
+            return Err(invalid("max_connections", "0", "must be greater than zero"));
+        }
+        if self.max_message_size == 0 {
+            return Err(invalid("max_message_size", "0", "must be greater than zero"));
+        }
+        if self.ping_interval.is_zero() {
+            return Err(invalid("ping_interval", "0", "must be greater than zero"));
+        }
+        if self.pong_timeout.is_zero() {
+            return Err(invalid("pong_timeout", "0", "must be greater than zero"));
+        }
+        if self.idle_timeout.is_some_and(|idle_timeout| idle_timeout.is_zero()) {
+            return Err(invalid("idle_timeout", "0", "must be greater than zero"));
+        }
+        if let Some(url) = &self.store_url {
+            if url != "memory" && !["sqlite://", "mysql://", "redis://"].iter().any(|scheme| url.starts_with(scheme)) {
+                return Err(invalid("store_url", url, "expected memory, sqlite://, mysql:// or redis://"));
+            }
+        }
+        if let Some(url) = &self.bus_url {
+            if url != "memory" && !url.starts_with("redis://") {
+                return Err(invalid("bus_url", url, "expected memory or redis://"));
+            }
+            // Nodes find each other's peers and rooms in the store, so it has to be shared.
+            if url != "memory" && self.store_url.as_deref().unwrap_or("memory") == "memory" {
+                return Err(invalid("bus_url", url, "requires a shared store_url"));
+            }
+        }
+        if self.jwt_secret.as_deref() == Some("") {
+            return Err(invalid("jwt_secret", "", "must not be empty"));
+        }
+        if self.jwt_secret.is_some() && !self.static_tokens.is_empty() {
+            return Err(invalid("static_tokens", "", "cannot be combined with jwt_secret"));
+        }
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(_), None) => return Err(ConfigError::MissingValue(String::from("tls_key"))),
+            (None, Some(_)) => return Err(ConfigError::MissingValue(String::from("tls_cert"))),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn parse<T>(key: &str, value: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value.trim().parse().map_err(|e: T::Err| invalid(key, value, &e.to_string()))
+}
+
+fn invalid(key: &str, value: &str, reason: &str) -> ConfigError {
+    ConfigError::InvalidValue {
+        key: key.to_string(),
+        value: value.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_settings_precedence() {
+        let path = std::env::temp_dir().join(format!("ws_server_{}.toml", std::process::id()));
+        fs::write(&path, "port = 9000\nws_path = \"/signal\"\nroom_policy = \"keep_until_empty\"\nmax_connections = 5\nallowed_origins = [\"https://a.example\", \"https://b.example/\"]\n").unwrap();
+
+        let env = |key: &str| match key {
+            "WS_SERVER_CONFIG" => Some(path.to_string_lossy().to_string()),
+            "WS_SERVER_PORT" => Some(String::from("9001")),
+            "WS_SERVER_MAX_CONNECTIONS" => Some(String::from("50")),
+            _ => None,
+        };
+        let settings: Settings = Settings::from_sources(args(&["--port", "9002", "--host=127.0.0.1", "--drain-timeout", "3", "--idle-timeout", "300"]), env).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(settings.listen_addr(), "127.0.0.1:9002".parse().unwrap());
+        assert_eq!(settings.ws_path, "/signal");
+        assert_eq!(settings.max_connections, 50);
+        assert_eq!(settings.room_policy, RoomPolicy::KeepUntilEmpty);
+        assert_eq!(settings.max_message_size, Settings::default().max_message_size);
+        assert_eq!(settings.drain_timeout, Duration::from_secs(3));
+        assert_eq!(settings.idle_timeout, Some(Duration::from_secs(300)));
+        assert_eq!(settings.resume_window, Settings::default().resume_window);
+        assert_eq!(settings.allowed_origins, vec![String::from("https://a.example"), String::from("https://b.example")]);
+    }
+
+    #[test]
+    fn test_settings_errors() {
+        let no_env = |_: &str| None;
+        assert_eq!(Settings::from_sources(args(&[]), no_env), Ok(Settings::default()));
+        assert_eq!(Settings::from_sources(args(&["--help"]), no_env), Err(ConfigError::Help));
+        assert_eq!(Settings::from_sources(args(&["--bogus", "1"]), no_env), Err(ConfigError::UnknownOption(String::from("bogus"))));
+        assert_eq!(Settings::from_sources(args(&["--port"]), no_env), Err(ConfigError::MissingValue(String::from("--port"))));
+        assert!(matches!(Settings::from_sources(args(&["--port", "host_port"]), no_env), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Settings::from_sources(args(&["--ws-path", "ws"]), no_env), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Settings::from_sources(args(&["--http-path", "/"]), no_env), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Settings::from_sources(args(&["--admin-path", "/http"]), no_env), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Settings::from_sources(args(&["--room-policy", "forever"]), no_env), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Settings::from_sources(args(&["--ping-interval", "0"]), no_env), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Settings::from_sources(args(&["--idle-timeout", "0"]), no_env), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Settings::from_sources(args(&["--store-url", "postgres://localhost"]), no_env), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Settings::from_sources(args(&["--bus-url", "nats://localhost"]), no_env), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Settings::from_sources(args(&["--bus-url", "redis://localhost"]), no_env), Err(ConfigError::InvalidValue { .. })));
+        assert!(Settings::from_sources(args(&["--bus-url", "redis://localhost", "--store-url", "redis://localhost"]), no_env).is_ok());
+        assert!(matches!(Settings::from_sources(args(&["--config", "/nonexistent.toml"]), no_env), Err(ConfigError::File { .. })));
+        assert_eq!(Settings::from_sources(args(&["--tls-cert", "cert.pem"]), no_env), Err(ConfigError::MissingValue(String::from("tls_key"))));
+        assert!(matches!(Settings::from_sources(args(&["--static-tokens", "token-only"]), no_env), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(
+            Settings::from_sources(args(&["--static-tokens", "t:alice", "--jwt-secret", "s"]), no_env),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,88 @@
+use serde_json::{json, Value};
+use std::fmt;
+use tracing::error;
+
+use crate::websocket::PeerId;
+use crate::websocket::store::StoreError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    InvalidMessage(String),
+    RoomNotFound,
+    RoomExists(String),
+    NotInRoom(PeerId),
+    PeerUnreachable(Option<PeerId>),
+    AccessDenied,
+    Forbidden(String),
+    Storage(String),
+}
+
+impl Error {
+    /// Machine-readable identifier clients can switch on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidMessage(_) => "invalid_message",
+            Error::RoomNotFound => "room_not_found",
+            Error::RoomExists(_) => "room_exists",
+            Error::NotInRoom(_) => "not_in_room",
+            Error::PeerUnreachable(_) => "peer_unreachable",
+            Error::AccessDenied => "access_denied",
+            Error::Forbidden(_) => "forbidden",
+            Error::Storage(_) => "storage_error",
+        }
+    }
+
+    /// The `error` message sent back to the client whose request failed.
+    pub fn reply(&self, request_id: Option<String>) -> Value {
+        json!({
+            "data_type": "error",
+            "code": self.code(),
+            "message": self.to_string(),
+            "request_id": request_id,
+        })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidMessage(reason) => write!(f, "The message is invalid: {}", reason),
+            Error::RoomNotFound => write!(f, "The room does not exist!"),
+            Error::RoomExists(room_id) => write!(f, "The room {} already exists!", room_id),
+            Error::NotInRoom(peer_id) => write!(f, "[{}] is not a member of the room!", peer_id),
+            Error::PeerUnreachable(Some(to)) => write!(f, "[{}] is not a member of the room!", to),
+            Error::PeerUnreachable(None) => write!(f, "The target peer is not reachable!"),
+            Error::AccessDenied => write!(f, "The room password or invite is invalid!"),
+            Error::Forbidden(action) => write!(f, "Your role does not allow you to {}!", action),
+            Error::Storage(_) => write!(f, "The room could not be read or saved, try again later!"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Clients only learn that storage failed; the reason goes to the log.
+impl From<StoreError> for Error {
+    fn from(e: StoreError) -> Self {
+        error!(error = %e, "storage request failed");
+        Error::Storage(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_reply() {
+        let reply: Value = Error::RoomExists(String::from("test_room")).reply(Some(String::from("req-1")));
+        assert_eq!(reply["data_type"], "error");
+        assert_eq!(reply["code"], "room_exists");
+        assert_eq!(reply["message"], "The room test_room already exists!");
+        assert_eq!(reply["request_id"], "req-1");
+
+        let reply: Value = Error::RoomNotFound.reply(None);
+        assert_eq!(reply["code"], "room_not_found");
+        assert!(reply["request_id"].is_null());
+    }
+}
@@ -1 +1,5 @@
+pub mod config;
+pub mod error;
+pub mod logging;
+pub mod metrics;
 pub mod websocket;
\ No newline at end of file
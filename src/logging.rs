@@ -0,0 +1,99 @@
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing_subscriber::{filter::LevelFilter, EnvFilter};
+
+use crate::config::{LogFormat, LogLevel, Settings};
+
+/// Fields whose contents [`redact_message`] hides: session descriptions and ICE
+/// candidates carry addresses and credentials.
+const REDACTED_FIELDS: [&str; 3] = ["sdp", "candidate", "usernameFragment"];
+
+static REDACT: AtomicBool = AtomicBool::new(true);
+
+/// Installs the global subscriber. `RUST_LOG`, when set, replaces `log_level` with a
+/// full filter directive such as `rust_websocket_server::websocket=debug,hyper=info`.
+pub fn init(settings: &Settings) {
+    REDACT.store(!settings.log_sdp, Ordering::Relaxed);
+
+    // Dependencies such as hyper are chatty below warn, so `log_level` only goes further for this crate.
+    let filter: EnvFilter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        let level: LevelFilter = level_filter(settings.log_level);
+        EnvFilter::new(format!("{},{}={}", level.min(LevelFilter::WARN), env!("CARGO_CRATE_NAME"), level))
+    });
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_target(false);
+    let installed = match settings.log_format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(true).try_init(),
+    };
+    if let Err(e) = installed {
+        eprintln!("failed to install the log subscriber: {}", e);
+    }
+}
+
+pub fn level_filter(level: LogLevel) -> LevelFilter {
+    match level {
+        LogLevel::Error => LevelFilter::ERROR,
+        LogLevel::Warn => LevelFilter::WARN,
+        LogLevel::Info => LevelFilter::INFO,
+        LogLevel::Debug => LevelFilter::DEBUG,
+        LogLevel::Trace => LevelFilter::TRACE,
+    }
+}
+
+/// SDP or candidate text as it may appear in logs: only its length unless `log_sdp` is on.
+pub fn redact(content: &str) -> String {
+    if REDACT.load(Ordering::Relaxed) {
+        format!("<redacted {} bytes>", content.len())
+    } else {
+        content.to_string()
+    }
+}
+
+/// A signaling message as it may appear in logs, with the [`REDACTED_FIELDS`] at any
+/// depth replaced through [`redact`]. Text that is not JSON is summarized by its length.
+pub fn redact_message(msg: &str) -> String {
+    if !REDACT.load(Ordering::Relaxed) {
+        return msg.to_string();
+    }
+    match serde_json::from_str::<Value>(msg) {
+        Ok(mut value) => {
+            redact_value(&mut value);
+            value.to_string()
+        },
+        Err(_) => format!("<{} bytes of non-JSON text>", msg.len()),
+    }
+}
+
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                match field {
+                    Value::String(content) if REDACTED_FIELDS.contains(&name.as_str()) => *content = redact(content),
+                    _ => redact_value(field),
+                }
+            }
+        },
+        Value::Array(items) => items.iter_mut().for_each(redact_value),
+        _ => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_message() {
+        let msg: &str = r#"{"data_type":"send_candidate","room_id":"r","candidate":{"candidate":"candidate:1 1 udp 2122260223 10.0.0.5 54321 typ host","sdpMid":"0","sdpMLineIndex":0,"usernameFragment":"abcd"}}"#;
+        let redacted: Value = serde_json::from_str(&redact_message(msg)).unwrap();
+        assert_eq!(redacted["room_id"], "r");
+        assert_eq!(redacted["candidate"]["sdpMid"], "0");
+        assert_eq!(redacted["candidate"]["candidate"], "<redacted 52 bytes>");
+        assert_eq!(redacted["candidate"]["usernameFragment"], "<redacted 4 bytes>");
+
+        let offer: &str = r#"{"data_type":"store_offer","offer":{"type":"offer","sdp":"v=0\r\no=- 1 2 IN IP4 10.0.0.5"}}"#;
+        assert!(!redact_message(offer).contains("10.0.0.5"));
+        assert_eq!(redact_message("not json"), "<8 bytes of non-JSON text>");
+    }
+}
\ No newline at end of file
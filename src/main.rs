@@ -1,7 +1,23 @@
-use rust_websocket_server::{*, websocket::ConnTrait};
+use std::process::exit;
+
+use rust_websocket_server::{*, config::{ConfigError, Settings}, websocket::ConnTrait};
 
 #[tokio::main]
 async fn main() {   
-    let mut websocket_conn: Box<dyn ConnTrait> = websocket::Config::new();
-    websocket_conn.init("host_ip", "host_port").await;
+    let settings: Settings = match Settings::load() {
+        Ok(settings) => settings,
+        Err(ConfigError::Help) => {
+            println!("{}", ConfigError::Help);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    };
+
+    logging::init(&settings);
+
+    let mut websocket_conn: Box<dyn ConnTrait> = websocket::Config::new(settings);
+    websocket_conn.init().await;
 }
\ No newline at end of file
@@ -0,0 +1,275 @@
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    future::Future,
+    sync::{atomic::{AtomicU64, Ordering}, Mutex},
+    time::{Duration, Instant}
+};
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+/// Process-wide registry scraped by the `/metrics` route.
+pub static METRICS: Metrics = Metrics::new();
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+const BUCKETS: [f64; 12] = [0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// The shared state whose lock wait times are recorded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lock {
+    PeerMap,
+    /// The rooms and peers of the in-memory storage; the other backends record nothing.
+    MemoryStore,
+}
+
+pub struct Counter {
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new() -> Self {
+        Counter { value: AtomicU64::new(0) }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// Counter partitioned by a single label, e.g. `data_type`.
+pub struct CounterVec {
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl CounterVec {
+    const fn new() -> Self {
+        CounterVec { values: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn inc(&self, label: &str) {
+        self.add(label, 1);
+    }
+
+    pub fn add(&self, label: &str, n: u64) {
+        let mut values = self.values.lock().expect("metrics lock poisoned!");
+        match values.get_mut(label) {
+            Some(value) => *value += n,
+            None => {
+                values.insert(label.to_string(), n);
+            }
+        }
+    }
+}
+
+struct HistogramData {
+    counts: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Latency histogram over [`BUCKETS`]. Bucket counts are stored per bucket and made
+/// cumulative when rendered.
+pub struct Histogram {
+    data: Mutex<HistogramData>,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            data: Mutex::new(HistogramData { counts: [0; BUCKETS.len()], sum: 0.0, count: 0 }),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds: f64 = elapsed.as_secs_f64();
+        let mut data = self.data.lock().expect("metrics lock poisoned!");
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            data.counts[bucket] += 1;
+        }
+        data.sum += seconds;
+        data.count += 1;
+    }
+}
+
+pub struct Metrics {
+    /// Signaling messages received, by `data_type`.
+    pub messages_in: CounterVec,
+    /// Messages queued for peers, by `data_type`.
+    pub messages_out: CounterVec,
+    /// Inbound messages that were not valid signaling JSON.
+    pub parse_errors: Counter,
+    /// Messages that could not be queued or written, by reason.
+    pub send_failures: CounterVec,
+    /// Upgrade and HTTP session requests that were refused, by reason.
+    pub upgrade_failures: CounterVec,
+    /// WebSocket peers disconnected for missing a pong or staying silent, by reason.
+    pub keepalive_timeouts: CounterVec,
+    /// Time taken to queue one message for all of its recipients.
+    pub fanout: Histogram,
+    peer_map_wait: Histogram,
+    memory_store_wait: Histogram,
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Metrics {
+            messages_in: CounterVec::new(),
+            messages_out: CounterVec::new(),
+            parse_errors: Counter::new(),
+            send_failures: CounterVec::new(),
+            upgrade_failures: CounterVec::new(),
+            keepalive_timeouts: CounterVec::new(),
+            fanout: Histogram::new(),
+            peer_map_wait: Histogram::new(),
+            memory_store_wait: Histogram::new(),
+        }
+    }
+
+    pub fn lock_wait(&self, lock: Lock) -> &Histogram {
+        match lock {
+            Lock::PeerMap => &self.peer_map_wait,
+            Lock::MemoryStore => &self.memory_store_wait,
+        }
+    }
+
+    /// Renders every metric in the Prometheus text exposition format. The gauges are
+    /// read off the live maps by the caller.
+    pub fn render(&self, connections: usize, suspended: usize, rooms: usize) -> String {
+        let mut out: String = String::new();
+        gauge(&mut out, "signaling_active_connections", "Peers connected over WebSocket or the HTTP fallback.", connections);
+        gauge(&mut out, "signaling_suspended_peers", "WebSocket peers whose connection dropped, held for a resume.", suspended);
+        gauge(&mut out, "signaling_active_rooms", "Rooms currently open.", rooms);
+        counter(&mut out, "signaling_messages_in_total", "Signaling messages received.", "data_type", &self.messages_in);
+        counter(&mut out, "signaling_messages_out_total", "Messages queued for peers.", "data_type", &self.messages_out);
+        header(&mut out, "signaling_parse_errors_total", "Inbound messages that failed to parse.", "counter");
+        let _ = writeln!(out, "signaling_parse_errors_total {}", self.parse_errors.get());
+        counter(&mut out, "signaling_send_failures_total", "Messages that could not be delivered to a peer.", "reason", &self.send_failures);
+        counter(&mut out, "signaling_upgrade_failures_total", "Connection attempts that were refused.", "reason", &self.upgrade_failures);
+        counter(&mut out, "signaling_keepalive_timeouts_total", "Peers disconnected by the keepalive.", "reason", &self.keepalive_timeouts);
+
+        let name: &str = "signaling_fanout_duration_seconds";
+        header(&mut out, name, "Time taken to queue one message for all of its recipients.", "histogram");
+        histogram(&mut out, name, "", &self.fanout);
+        let name: &str = "signaling_lock_wait_seconds";
+        header(&mut out, name, "Time spent waiting on the peer map or the in-memory storage.", "histogram");
+        histogram(&mut out, name, "lock=\"peer_map\",", &self.peer_map_wait);
+        histogram(&mut out, name, "lock=\"memory_store\",", &self.memory_store_wait);
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+/// Awaits `acquire`, a lock on one of the shared maps, and records how long it took.
+pub async fn timed_lock<F: Future>(lock: Lock, acquire: F) -> F::Output {
+    let started: Instant = Instant::now();
+    let guard: F::Output = acquire.await;
+    METRICS.lock_wait(lock).observe(started.elapsed());
+    guard
+}
+
+/// The `data_type` of an outbound message, `close` for close frames.
+pub fn data_type_of(msg: &Message) -> String {
+    #[derive(Deserialize)]
+    struct Tagged {
+        data_type: String,
+    }
+
+    match msg {
+        Message::Text(text) => serde_json::from_str::<Tagged>(text)
+            .map(|tagged| tagged.data_type)
+            .unwrap_or_else(|_| String::from("unknown")),
+        Message::Close(_) => String::from("close"),
+        _ => String::from("other"),
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, label: &str, counter: &CounterVec) {
+    header(out, name, help, "counter");
+    let values = counter.values.lock().expect("metrics lock poisoned!");
+    for (value, count) in values.iter() {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, escape(value), count);
+    }
+}
+
+/// `labels` is prepended to the `le` label and must end with a comma when non-empty.
+fn histogram(out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    let data = histogram.data.lock().expect("metrics lock poisoned!");
+    let mut cumulative: u64 = 0;
+    for (bound, count) in BUCKETS.iter().zip(data.counts.iter()) {
+        cumulative += count;
+        let _ = writeln!(out, "{}_bucket{{{}le=\"{}\"}} {}", name, labels, bound, cumulative);
+    }
+    let _ = writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, labels, data.count);
+    let labels: &str = labels.trim_end_matches(',');
+    let braces: String = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+    let _ = writeln!(out, "{}_sum{} {}", name, braces, data.sum);
+    let _ = writeln!(out, "{}_count{} {}", name, braces, data.count);
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_metrics() {
+        let metrics: Metrics = Metrics::new();
+        metrics.messages_in.inc("join_call");
+        metrics.messages_in.inc("join_call");
+        metrics.messages_out.add("peer_joined", 3);
+        metrics.parse_errors.inc();
+        metrics.upgrade_failures.inc("origin_not_allowed");
+        metrics.keepalive_timeouts.inc("pong_timeout");
+        metrics.fanout.observe(Duration::from_micros(200));
+        metrics.fanout.observe(Duration::from_secs(10));
+        metrics.lock_wait(Lock::MemoryStore).observe(Duration::from_micros(5));
+
+        let text: String = metrics.render(4, 1, 2);
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"# TYPE signaling_active_connections gauge"));
+        assert!(lines.contains(&"signaling_active_connections 4"));
+        assert!(lines.contains(&"signaling_suspended_peers 1"));
+        assert!(lines.contains(&"signaling_active_rooms 2"));
+        assert!(lines.contains(&"signaling_messages_in_total{data_type=\"join_call\"} 2"));
+        assert!(lines.contains(&"signaling_messages_out_total{data_type=\"peer_joined\"} 3"));
+        assert!(lines.contains(&"signaling_parse_errors_total 1"));
+        assert!(lines.contains(&"signaling_upgrade_failures_total{reason=\"origin_not_allowed\"} 1"));
+        assert!(lines.contains(&"signaling_keepalive_timeouts_total{reason=\"pong_timeout\"} 1"));
+        assert!(lines.contains(&"signaling_fanout_duration_seconds_bucket{le=\"0.0001\"} 0"));
+        assert!(lines.contains(&"signaling_fanout_duration_seconds_bucket{le=\"0.0005\"} 1"));
+        assert!(lines.contains(&"signaling_fanout_duration_seconds_bucket{le=\"5\"} 1"));
+        assert!(lines.contains(&"signaling_fanout_duration_seconds_bucket{le=\"+Inf\"} 2"));
+        assert!(lines.contains(&"signaling_fanout_duration_seconds_count 2"));
+        assert!(lines.contains(&"signaling_lock_wait_seconds_bucket{lock=\"memory_store\",le=\"0.00001\"} 1"));
+        assert!(lines.contains(&"signaling_lock_wait_seconds_count{lock=\"peer_map\"} 0"));
+    }
+
+    #[test]
+    fn test_data_type_of() {
+        assert_eq!(data_type_of(&Message::Text(String::from(r#"{"data_type":"offer","sdp":"v=0"}"#))), "offer");
+        assert_eq!(data_type_of(&Message::Text(String::from("not json"))), "unknown");
+        assert_eq!(data_type_of(&Message::Close(None)), "close");
+    }
+}
\ No newline at end of file
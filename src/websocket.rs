@@ -1,5 +1,13 @@
+pub mod admin;
+pub mod auth;
+pub mod bus;
 pub mod data_transfer;
+pub mod fallback;
 pub mod handler;
+pub mod keepalive;
+pub mod resume;
+pub mod store;
+pub mod tls;
 pub mod webrtc;
 
 use async_trait::async_trait;
@@ -10,7 +18,7 @@ use hyper::{
     Body, 
     Request, 
     Server, 
-    server::conn::AddrStream, 
+    server::conn::{AddrStream, Http}, 
     service::{make_service_fn, service_fn},
     upgrade::Upgraded
 };
@@ -18,31 +26,89 @@ use std::{
     collections::HashMap, 
     convert::Infallible,
     net::SocketAddr, 
-    sync::Arc
+    sync::Arc,
+    time::Duration
 };
-use tokio::sync::Mutex;
+use tokio::{
+    net::TcpListener, 
+    select, 
+    spawn, 
+    sync::{mpsc::Sender, oneshot, Mutex, RwLock}, 
+    time::{sleep, timeout, Instant}
+};
+use tracing::{error, info, warn};
+use uuid::Uuid;
 use tokio_tungstenite::{
-    tungstenite::protocol::Message, 
+    tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, Message}, 
     WebSocketStream
 };
 
+use crate::config::Settings;
+use crate::websocket::auth::{Authenticator, Identity, JwtAuthenticator, StaticTokenAuthenticator};
+use crate::websocket::bus::Bus;
+use crate::websocket::bus::local::LocalHub;
+use crate::websocket::fallback::{HttpFallback, HttpSession};
 use crate::websocket::handler::{Handler, RouterTrait, Router};
-use crate::websocket::data_transfer::Room;
+use crate::websocket::resume::Resumable;
+use crate::websocket::store::{PeerRecord, Storage};
+use crate::websocket::store::memory::MemoryStorage;
+use crate::websocket::tls::ReloadableTls;
 
 type StreamWrite = SplitSink<WebSocketStream<Upgraded>, Message>;
 type StreamRead = SplitStream<WebSocketStream<Upgraded>>;
-type PeerMap = Arc<Mutex<HashMap<SocketAddr, StreamWrite>>>;
+pub type PeerId = String;
+type PeerMap = Arc<RwLock<HashMap<PeerId, Peer>>>;
+
+pub type RoomId = String;
+type SharedStorage = Arc<dyn Storage>;
+
+pub type NodeId = String;
+type SharedBus = Arc<dyn Bus>;
+
+type HttpSessions = Arc<RwLock<HashMap<String, HttpSession>>>;
+
+type Resumptions = Arc<Mutex<HashMap<String, Resumable>>>;
 
-type ChatRoom = Arc<Mutex<Room>>;
-type ChatRooms = Arc<Mutex<HashMap<SocketAddr, ChatRoom>>>;
+type SharedAuthenticator = Option<Arc<dyn Authenticator>>;
+
+/// Outbound frames queued per peer; a lagging peer only backs up its own queue.
+const OUTBOUND_QUEUE_SIZE: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub tx: Sender<Message>,
+    /// Set when the server requires authentication.
+    pub identity: Option<Identity>,
+}
 
 pub struct Config;
 impl Config {
-    pub fn new() -> Box<dyn ConnTrait> {
+    /// Builds the server with the authenticator described by `settings`, if any.
+    pub fn new(settings: Settings) -> Box<dyn ConnTrait> {
+        let authenticator: SharedAuthenticator = match &settings.jwt_secret {
+            Some(secret) => Some(Arc::new(JwtAuthenticator::new(
+                secret.as_bytes(),
+                settings.jwt_issuer.clone(),
+                settings.jwt_audience.clone(),
+            ))),
+            None if !settings.static_tokens.is_empty() => Some(Arc::new(StaticTokenAuthenticator::new(settings.static_tokens.clone()))),
+            None => None,
+        };
+        Config::with_authenticator(settings, authenticator)
+    }
+
+    /// Builds the server with a custom [`Authenticator`]; `None` accepts anonymous peers.
+    pub fn with_authenticator(settings: Settings, authenticator: Option<Arc<dyn Authenticator>>) -> Box<dyn ConnTrait> {
         Box::new(
             Conn {
-                ws_peers: Arc::new(Mutex::new(HashMap::new())),
-                ws_rooms: Arc::new(Mutex::new(HashMap::new())),
+                ws_peers: Arc::new(RwLock::new(HashMap::new())),
+                storage: Arc::new(MemoryStorage::new()),
+                bus: Arc::new(LocalHub::new().join(Uuid::new_v4().to_string())),
+                http_sessions: Arc::new(RwLock::new(HashMap::new())),
+                resumptions: Arc::new(Mutex::new(HashMap::new())),
+                settings: Arc::new(settings),
+                authenticator,
             }
         )
     }
@@ -50,35 +116,344 @@ impl Config {
 
 #[async_trait]
 pub trait ConnTrait {
-    async fn init(&mut self, ip: &str, port: &str);
+    async fn init(&mut self);
 }
 
 pub struct Conn {
     ws_peers: PeerMap,
-    ws_rooms: ChatRooms,
+    storage: SharedStorage,
+    bus: SharedBus,
+    http_sessions: HttpSessions,
+    resumptions: Resumptions,
+    settings: Arc<Settings>,
+    authenticator: SharedAuthenticator,
 }
 #[async_trait]
 impl ConnTrait for Conn {
-    async fn init(&mut self, ip: &str, port: &str) {                        
+    async fn init(&mut self) {                        
+        if let Some(url) = &self.settings.store_url {
+            self.storage = match store::open(url).await {
+                Ok(storage) => storage,
+                Err(e) => {
+                    error!(error = %e, "failed to open the storage");
+                    return;
+                }
+            };
+            match self.storage.room_count().await {
+                Ok(rooms) => info!(rooms, "opened the storage"),
+                Err(e) => {
+                    error!(error = %e, "failed to read from the storage");
+                    return;
+                }
+            }
+        }
+
+        if let Some(url) = &self.settings.bus_url {
+            self.bus = match bus::open(url, Uuid::new_v4().to_string()).await {
+                Ok(bus) => bus,
+                Err(e) => {
+                    error!(error = %e, "failed to connect to the message bus");
+                    return;
+                }
+            };
+        }
+        match self.bus.subscribe().await {
+            Ok(rx) => {
+                info!(node_id = %self.bus.node_id(), "joined the message bus");
+                spawn(bus::forward(rx, self.storage.clone(), self.ws_peers.clone(), self.bus.clone()));
+                spawn(bus::reap_gone(self.storage.clone(), self.ws_peers.clone(), self.bus.clone(), bus::REAP_GRACE));
+            },
+            Err(e) => {
+                error!(error = %e, "failed to subscribe to the message bus");
+                return;
+            }
+        }
+
+        spawn(HttpFallback::reap_idle(
+            self.http_sessions.clone(),
+            self.ws_peers.clone(),
+            self.bus.clone(),
+            self.storage.clone(),
+            self.settings.http_session_timeout,
+        ));
+
+        if let (Some(cert), Some(key)) = (&self.settings.tls_cert, &self.settings.tls_key) {
+            match ReloadableTls::load(cert, key) {
+                Ok(tls) => self.serve_tls(tls).await,
+                Err(e) => error!(error = %e, "failed to load TLS certificate"),
+            }
+            return;
+        }
+
         let make_svc = make_service_fn(|socket: &AddrStream| {
             let peers: PeerMap = self.ws_peers.clone();
-            let rooms: ChatRooms = self.ws_rooms.clone();
+            let storage: SharedStorage = self.storage.clone();
+            let bus: SharedBus = self.bus.clone();
+            let sessions: HttpSessions = self.http_sessions.clone();
+            let resumptions: Resumptions = self.resumptions.clone();
+            let settings: Arc<Settings> = self.settings.clone();
+            let authenticator: SharedAuthenticator = self.authenticator.clone();
             let addr: SocketAddr = socket.remote_addr();
             
             async move {
                 Ok::<_, Infallible>(service_fn(move |req: Request<Body>| { 
                     let handler: Router = Handler::new();                    
-                    handler.router(req, rooms.clone(), peers.clone(), addr)
+                    handler.router(req, storage.clone(), peers.clone(), bus.clone(), sessions.clone(), resumptions.clone(), addr, settings.clone(), authenticator.clone())
                 }))
             }
         });
     
-        let addr_url: SocketAddr = format!("{}:{}", ip, port).parse().expect("address url parsed error!");
-        let server = Server::bind(&addr_url).serve(make_svc);
-        println!("Running Websocket Server [{}]...", addr_url);
-        
-        if let Err(e) = server.await {
-            eprintln!("server error: {}", e);
+        let addr_url: SocketAddr = self.settings.listen_addr();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = match Server::try_bind(&addr_url) {
+            Ok(builder) => builder.serve(make_svc).with_graceful_shutdown(async { stopped.await.ok(); }),
+            Err(e) => {
+                error!(addr = %addr_url, error = %e, "failed to bind");
+                return;
+            }
+        };
+        info!(addr = %addr_url, ws_path = %self.settings.ws_path, "Running Websocket Server...");
+        tokio::pin!(server);
+
+        let served: Result<(), hyper::Error> = select! {
+            served = &mut server => {
+                self.shutdown().await;
+                served
+            },
+            _ = shutdown_signal() => {
+                let _ = stop.send(());
+                // Hyper waits for the responses still streaming, such as `/events`, which
+                // only end once the drain closes their peers, so both run at once.
+                let draining = spawn(shutdown(self.resumptions.clone(), self.ws_peers.clone(), self.storage.clone(), self.settings.drain_timeout));
+                let served: Result<(), hyper::Error> = timeout(self.settings.drain_timeout, &mut server).await.unwrap_or_else(|_| {
+                    warn!("Drain period over, dropping the remaining requests.");
+                    Ok(())
+                });
+                let _ = draining.await;
+                served
+            },
+        };
+        if let Err(e) = served {
+            error!(error = %e, "server error");
+        }
+    }
+}
+
+impl Conn {
+    /// Accepts `wss://` connections. Hyper 0.14 has no TLS acceptor of its own, so the
+    /// handshake runs here and each stream is then served with upgrades enabled.
+    async fn serve_tls(&self, tls: Arc<ReloadableTls>) {
+        let addr_url: SocketAddr = self.settings.listen_addr();
+        let listener: TcpListener = match TcpListener::bind(addr_url).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!(addr = %addr_url, error = %e, "failed to bind");
+                return;
+            }
+        };
+        #[cfg(unix)]
+        spawn(tls.clone().reload_on_sighup());
+        info!(addr = %addr_url, ws_path = %self.settings.ws_path, tls = true, "Running Websocket Server...");
+
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
+        loop {
+            let (stream, addr) = select! {
+                _ = &mut shutdown => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!(error = %e, "failed to accept connection");
+                        continue;
+                    }
+                },
+            };
+            let acceptor = tls.acceptor();
+            let peers: PeerMap = self.ws_peers.clone();
+            let storage: SharedStorage = self.storage.clone();
+            let bus: SharedBus = self.bus.clone();
+            let sessions: HttpSessions = self.http_sessions.clone();
+            let resumptions: Resumptions = self.resumptions.clone();
+            let settings: Arc<Settings> = self.settings.clone();
+            let authenticator: SharedAuthenticator = self.authenticator.clone();
+
+            spawn(async move {
+                let tls_stream = match acceptor.accept(stream).await {
+                    Ok(tls_stream) => tls_stream,
+                    Err(e) => {
+                        warn!(addr = %addr, error = %e, "TLS handshake failed");
+                        return;
+                    }
+                };
+                let service = service_fn(move |req: Request<Body>| {
+                    let handler: Router = Handler::new();
+                    handler.router(req, storage.clone(), peers.clone(), bus.clone(), sessions.clone(), resumptions.clone(), addr, settings.clone(), authenticator.clone())
+                });
+                if let Err(e) = Http::new().serve_connection(tls_stream, service).with_upgrades().await {
+                    warn!(addr = %addr, error = %e, "connection error");
+                }
+            });
+        }
+        drop(listener);
+        self.shutdown().await;
+    }
+
+    async fn shutdown(&self) {
+        shutdown(self.resumptions.clone(), self.ws_peers.clone(), self.storage.clone(), self.settings.drain_timeout).await;
+    }
+}
+
+/// Drops the suspended peers, then [`drain`]s the connected ones.
+async fn shutdown(resumptions: Resumptions, peers: PeerMap, storage: SharedStorage, timeout: Duration) {
+    resume::discard(&resumptions, &peers).await;
+    drain(&peers, &storage, timeout).await;
+}
+
+/// Resolves on SIGINT, or SIGTERM on unix, whichever comes first.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => { terminate.recv().await; },
+            Err(e) => {
+                error!(error = %e, "failed to listen for SIGTERM");
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate => {},
+    }
+    info!("Shutting down, no longer accepting connections...");
+}
+
+/// Asks every connected peer to go away and waits up to `timeout` for them to hang up.
+/// Peers are unregistered from the storage meanwhile, so their rooms outlive the shutdown
+/// for a persistent backend instead of emptying as the connections close. Their records
+/// are put back afterwards, still naming this node, so whichever node starts next reaps
+/// them and the rooms close by their policy, see [`bus::reap_gone`].
+async fn drain(peers: &PeerMap, storage: &SharedStorage, timeout: Duration) {
+    let going_away: Message = Message::Close(Some(CloseFrame {
+        code: CloseCode::Away,
+        reason: "server shutting down".into(),
+    }));
+    let connected: Vec<(PeerId, Sender<Message>)> = peers.read().await.iter()
+        .map(|(peer_id, peer)| (peer_id.clone(), peer.tx.clone()))
+        .collect();
+    let mut drained: Vec<PeerRecord> = Vec::new();
+    for (peer_id, tx) in connected {
+        match storage.peer(&peer_id).await {
+            Ok(Some(record)) => drained.push(record),
+            Ok(None) => {},
+            Err(e) => warn!(peer_id = %peer_id, error = %e, "failed to look up the peer"),
+        }
+        if let Err(e) = storage.remove_peer(&peer_id).await {
+            warn!(peer_id = %peer_id, error = %e, "failed to unregister the peer");
+        }
+        if let Err(e) = tx.try_send(going_away.clone()) {
+            warn!(peer_id = %peer_id, error = %e, "failed to queue close frame");
+        }
+    }
+
+    let deadline: Instant = Instant::now() + timeout;
+    loop {
+        let remaining_peers: usize = peers.read().await.len();
+        if remaining_peers == 0 {
+            info!("All connections drained.");
+            break;
+        }
+        if Instant::now() >= deadline {
+            warn!(peers = remaining_peers, "Drain period over, dropping the remaining peers.");
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    for record in drained {
+        if let Err(e) = storage.insert_peer(&record).await {
+            warn!(peer_id = %record.peer_id, error = %e, "failed to keep the peer for reaping");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use tokio::sync::mpsc::{channel, Receiver};
+
+    use super::*;
+    use crate::websocket::data_transfer::{register, DataTransfer, DataType, Room, RoomPolicy};
+    use crate::websocket::store::sqlite::SqliteStorage;
+
+    /// Registers `peer_id` with a room of its own and hangs it up, like its read loop
+    /// would, once it gets a close frame.
+    async fn connect(storage: &SharedStorage, peers: &PeerMap, bus: &SharedBus, peer_id: &str) -> tokio::task::JoinHandle<Option<Message>> {
+        let (tx, mut rx): (Sender<Message>, Receiver<Message>) = channel(OUTBOUND_QUEUE_SIZE);
+        register(storage, peers, bus, peer_id.to_string(), Peer { addr: "127.0.0.1:8080".parse().unwrap(), tx, identity: None }).await.unwrap();
+        assert!(storage.insert_room(&Room::new(String::from("test_room"), peer_id.to_string(), RoomPolicy::default())).await.unwrap());
+
+        let (storage, peers, bus, peer_id) = (storage.clone(), peers.clone(), bus.clone(), peer_id.to_string());
+        spawn(async move {
+            let msg: Option<Message> = rx.recv().await;
+            DataType::close(storage, peers, bus, peer_id).await;
+            msg
+        })
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let peers: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let bus: SharedBus = Arc::new(LocalHub::new().join(String::from("node_a")));
+        drain(&peers, &storage, Duration::from_secs(60)).await;
+
+        // The peer hangs up once it sees the close frame, which ends the drain early.
+        let client = connect(&storage, &peers, &bus, "peer_1").await;
+        drain(&peers, &storage, Duration::from_secs(60)).await;
+
+        match client.await.unwrap() {
+            Some(Message::Close(Some(frame))) => assert_eq!(frame.code, CloseCode::Away),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        assert!(peers.read().await.is_empty());
+        // The room is kept for whoever serves it after the restart, and the peer for
+        // that node to reap.
+        assert_eq!(storage.peer("peer_1").await.unwrap().unwrap().node_id, "node_a");
+        assert_eq!(storage.room_count().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_drain_restart() {
+        let path: PathBuf = std::env::temp_dir().join(format!("signaling_{}.db", Uuid::new_v4().simple()));
+        {
+            let storage: SharedStorage = Arc::new(SqliteStorage::open(path.to_str().unwrap()).await.unwrap());
+            let peers: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+            let bus: SharedBus = Arc::new(LocalHub::new().join(String::from("node_a")));
+            let client = connect(&storage, &peers, &bus, "peer_1").await;
+            shutdown(Arc::new(Mutex::new(HashMap::new())), peers, storage.clone(), Duration::from_secs(60)).await;
+            client.await.unwrap();
+            assert_eq!(storage.room_count().await.unwrap(), 1);
+        }
+
+        // The next node to start over the same store reaps the peers of the one before,
+        // and their rooms close by their policy.
+        let storage: SharedStorage = Arc::new(SqliteStorage::open(path.to_str().unwrap()).await.unwrap());
+        assert_eq!(storage.room_count().await.unwrap(), 1);
+        let peers: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let bus: SharedBus = Arc::new(LocalHub::new().join(String::from("node_b")));
+        bus::reap_gone(storage.clone(), peers.clone(), bus.clone(), Duration::ZERO).await;
+        assert_eq!(storage.peer("peer_1").await.unwrap(), None);
+        assert_eq!(storage.room_count().await.unwrap(), 0);
+
+        // So the owner, back with a new peer id, can create the room again.
+        let _client = connect(&storage, &peers, &bus, "peer_2").await;
+        drop(storage);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
         }
     }
 }
\ No newline at end of file
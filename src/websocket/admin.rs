@@ -0,0 +1,339 @@
+use hyper::{
+    Body,
+    Method,
+    Request,
+    Response,
+    StatusCode,
+    body::{to_bytes, Bytes},
+    header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE}
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio::spawn;
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, Message};
+use tracing::{info, warn};
+
+use crate::config::Settings;
+use crate::websocket::bus;
+use crate::websocket::data_transfer::{deliver, DataTransfer, DataType, Room};
+use crate::websocket::handler::{empty_response, json_response, reject, Rejection};
+use crate::websocket::store::{PeerRecord, StoreError, Versioned};
+
+use super::NodeId;
+use super::PeerId;
+use super::PeerMap;
+use super::SharedBus;
+use super::SharedStorage;
+
+/// Body of `POST /rooms/{room_id}/notice`.
+#[derive(Debug, Deserialize)]
+struct Notice {
+    message: String,
+}
+
+/// Operator endpoints under `admin_path`, each requiring `Authorization: Bearer <admin_token>`:
+///
+/// - `GET /rooms` lists rooms with their member counts
+/// - `GET /rooms/{room_id}` shows a room and its participants
+/// - `DELETE /rooms/{room_id}` closes a room, sending its members `room_closed`
+/// - `POST /rooms/{room_id}/notice` sends `{"message": ...}` to every member as a `notice`
+/// - `DELETE /peers/{peer_id}` disconnects a peer with close code 1008 (policy violation)
+pub struct AdminApi;
+impl AdminApi {
+    pub async fn route(req: Request<Body>, storage: SharedStorage, peers: PeerMap, bus: SharedBus, settings: &Settings) -> Response<Body> {
+        let admin_token: &str = match &settings.admin_token {
+            Some(admin_token) => admin_token,
+            None => return reject(StatusCode::NOT_FOUND, "Not found").response(),
+        };
+        if !authorized(&req, admin_token) {
+            let mut res: Response<Body> = reject(StatusCode::UNAUTHORIZED, "Invalid admin token").response();
+            res.headers_mut().insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return res;
+        }
+
+        let route: Vec<String> = req.uri().path()[settings.admin_path.len()..]
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(String::from)
+            .collect();
+        let route: Vec<&str> = route.iter().map(String::as_str).collect();
+
+        let res: Result<Response<Body>, Rejection> = match (req.method(), route.as_slice()) {
+            (&Method::GET, ["rooms"]) => Self::list_rooms(storage).await,
+            (&Method::GET, ["rooms", room_id]) => Self::show_room(storage, room_id).await,
+            (&Method::DELETE, ["rooms", room_id]) => Self::close_room(storage, peers, bus, room_id).await,
+            (&Method::POST, ["rooms", room_id, "notice"]) => {
+                let room_id: String = room_id.to_string();
+                Self::notice(req, storage, peers, bus, &room_id, settings).await
+            },
+            (&Method::DELETE, ["peers", peer_id]) => Self::kick_peer(storage, peers, bus, peer_id.to_string()).await,
+            _ => Err(reject(StatusCode::NOT_FOUND, "Not found")),
+        };
+        res.unwrap_or_else(|rejection| rejection.response())
+    }
+
+    async fn list_rooms(storage: SharedStorage) -> Result<Response<Body>, Rejection> {
+        let rooms: Vec<Room> = storage.rooms().await.map_err(unavailable)?;
+        let mut list: Vec<Value> = rooms.iter().map(|room| json!({
+            "room_id": room.room_id,
+            "owner": room.owner,
+            "policy": room.policy,
+            "invite_only": room.access.invite_only,
+            "members": room.participants.len(),
+        })).collect();
+        list.sort_by(|a, b| a["room_id"].as_str().cmp(&b["room_id"].as_str()));
+        Ok(json_response(StatusCode::OK, &json!({ "rooms": list })))
+    }
+
+    async fn show_room(storage: SharedStorage, room_id: &str) -> Result<Response<Body>, Rejection> {
+        let room: Room = stored_room(&storage, room_id).await?.value;
+        let mut participants: Vec<Value> = Vec::new();
+        for participant in room.participants.iter() {
+            let peer: Option<PeerRecord> = storage.peer(&participant.peer_id).await.map_err(unavailable)?;
+            participants.push(json!({
+                "peer_id": participant.peer_id,
+                "role": participant.role,
+                "user_id": peer.as_ref().and_then(|peer| peer.user_id.clone()),
+                "addr": peer.as_ref().map(|peer| peer.addr.to_string()),
+                "node_id": peer.as_ref().map(|peer| peer.node_id.clone()),
+                "has_offer": participant.offer.is_some(),
+                "candidates": participant.candidates.len(),
+            }));
+        }
+
+        Ok(json_response(StatusCode::OK, &json!({
+            "room_id": room.room_id,
+            "owner": room.owner,
+            "policy": room.policy,
+            "invite_only": room.access.invite_only,
+            "default_role": room.access.default_role,
+            "participants": participants,
+        })))
+    }
+
+    async fn close_room(storage: SharedStorage, peers: PeerMap, bus: SharedBus, room_id: &str) -> Result<Response<Body>, Rejection> {
+        let members: Vec<PeerId> = loop {
+            let Versioned { value: room, version } = stored_room(&storage, room_id).await?;
+            if storage.remove_room(room_id, version).await.map_err(unavailable)? {
+                break room.members();
+            }
+        };
+        info!(room_id = %room_id, members = members.len(), "room closed by admin");
+
+        let closed_data: Value = json!({
+            "data_type": "room_closed",
+            "room_id": room_id,
+            "reason": "closed_by_admin",
+        });
+        deliver(storage, peers, bus, &members, Message::Text(closed_data.to_string())).await;
+        Ok(empty_response(StatusCode::NO_CONTENT))
+    }
+
+    async fn notice(req: Request<Body>, storage: SharedStorage, peers: PeerMap, bus: SharedBus, room_id: &str, settings: &Settings) -> Result<Response<Body>, Rejection> {
+        let body: Bytes = to_bytes(req.into_body()).await
+            .map_err(|e| reject(StatusCode::BAD_REQUEST, e.to_string()))?;
+        if body.len() > settings.max_message_size {
+            return Err(reject(StatusCode::PAYLOAD_TOO_LARGE, "The notice is too large"));
+        }
+        let notice: Notice = serde_json::from_slice(&body)
+            .map_err(|e| reject(StatusCode::BAD_REQUEST, format!("Invalid notice: {}", e)))?;
+
+        let members: Vec<PeerId> = stored_room(&storage, room_id).await?.value.members();
+
+        let notice_data: Value = json!({
+            "data_type": "notice",
+            "room_id": room_id,
+            "message": notice.message,
+        });
+        deliver(storage, peers, bus, &members, Message::Text(notice_data.to_string())).await;
+        Ok(json_response(StatusCode::ACCEPTED, &json!({ "delivered": members.len() })))
+    }
+
+    /// A peer connected to another node is closed by that node once the close frame
+    /// reaches it over the bus. When that node is not listening, the peer is closed here
+    /// and the node's other peers are reaped.
+    async fn kick_peer(storage: SharedStorage, peers: PeerMap, bus: SharedBus, peer_id: PeerId) -> Result<Response<Body>, Rejection> {
+        let peer: PeerRecord = storage.peer(&peer_id).await.map_err(unavailable)?
+            .ok_or_else(|| reject(StatusCode::NOT_FOUND, "Unknown peer"))?;
+        info!(peer_id = %peer_id, node_id = %peer.node_id, "peer disconnected by admin");
+
+        let farewell: Message = Message::Close(Some(CloseFrame {
+            code: CloseCode::Policy,
+            reason: "removed by an administrator".into(),
+        }));
+        if peer.node_id != bus.node_id() {
+            let unreachable: Vec<NodeId> = bus::relay(&storage, &bus, std::slice::from_ref(&peer_id), &farewell).await;
+            if unreachable.is_empty() {
+                return Ok(empty_response(StatusCode::NO_CONTENT));
+            }
+            spawn(bus::reap(storage.clone(), peers.clone(), bus.clone(), peer.node_id, bus::REAP_GRACE));
+        }
+        // Queued ahead of dropping the peer, so the writer still flushes it before the socket goes.
+        if let Some(peer) = peers.read().await.get(&peer_id) {
+            let _ = peer.tx.try_send(farewell);
+        }
+        DataType::close(storage, peers, bus, peer_id).await;
+        Ok(empty_response(StatusCode::NO_CONTENT))
+    }
+}
+
+/// Compares digests of the tokens, which have the same length whatever was sent, in
+/// constant time, so the time taken does not reveal how much of a guess was right.
+fn authorized(req: &Request<Body>, admin_token: &str) -> bool {
+    req.headers().get(AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.strip_prefix("Bearer "))
+    .is_some_and(|token| Sha256::digest(token.trim().as_bytes()).ct_eq(&Sha256::digest(admin_token.as_bytes())).into())
+}
+
+async fn stored_room(storage: &SharedStorage, room_id: &str) -> Result<Versioned<Room>, Rejection> {
+    storage.room(room_id).await.map_err(unavailable)?.ok_or_else(|| reject(StatusCode::NOT_FOUND, "Unknown room"))
+}
+
+/// The reason a storage request failed is logged rather than returned.
+fn unavailable(e: StoreError) -> Rejection {
+    warn!(error = %e, "storage request failed");
+    reject(StatusCode::SERVICE_UNAVAILABLE, "Storage unavailable")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::Arc
+    };
+    use tokio::{spawn, sync::{mpsc::{channel, Receiver}, RwLock}};
+
+    use crate::websocket::bus::local::LocalHub;
+    use crate::websocket::data_transfer::{register, RoomAccess, RoomPolicy};
+    use crate::websocket::store::memory::MemoryStorage;
+    use crate::websocket::{Peer, OUTBOUND_QUEUE_SIZE};
+
+    use super::*;
+
+    async fn test_peer(storage: &SharedStorage, peers: &PeerMap, bus: &SharedBus, peer_id: &str) -> Receiver<Message> {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let (tx, rx) = channel(OUTBOUND_QUEUE_SIZE);
+        register(storage, peers, bus, peer_id.to_string(), Peer { addr, tx, identity: None }).await.unwrap();
+        rx
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn request(storage: &SharedStorage, peers: &PeerMap, bus: &SharedBus, settings: &Settings, method: Method, uri: &str, token: &str, body: &str) -> (StatusCode, Value) {
+        let req: Request<Body> = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(body.to_string())).unwrap();
+        let res: Response<Body> = AdminApi::route(req, storage.clone(), peers.clone(), bus.clone(), settings).await;
+        let status: StatusCode = res.status();
+        let body: Bytes = to_bytes(res.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn next_msg(rx: &mut Receiver<Message>) -> Message {
+        rx.try_recv().expect("no queued message")
+    }
+
+    async fn recv_value(rx: &mut Receiver<Message>) -> Value {
+        serde_json::from_str(&rx.recv().await.expect("the queue closed").to_string()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_admin_api() {
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let peers: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let bus: SharedBus = Arc::new(LocalHub::new().join(String::from("node_a")));
+        let settings: Settings = Settings { admin_token: Some(String::from("secret")), ..Settings::default() };
+
+        let mut host_rx = test_peer(&storage, &peers, &bus, "host").await;
+        let mut guest_rx = test_peer(&storage, &peers, &bus, "guest").await;
+        for room_id in ["room_a", "room_b"] {
+            DataType::store_room(storage.clone(), room_id.to_string(), String::from("host"), RoomPolicy::default(), RoomAccess::default()).await.unwrap();
+        }
+        DataType::join_call(storage.clone(), String::from("room_a"), peers.clone(), bus.clone(), String::from("guest"), None, None).await.unwrap();
+        while host_rx.try_recv().is_ok() {}
+        while guest_rx.try_recv().is_ok() {}
+
+        let (status, _) = request(&storage, &peers, &bus, &settings, Method::GET, "/admin/rooms", "wrong", "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, list) = request(&storage, &peers, &bus, &settings, Method::GET, "/admin/rooms", "secret", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(list["rooms"][0]["room_id"], "room_a");
+        assert_eq!(list["rooms"][0]["members"], 2);
+        assert_eq!(list["rooms"][1]["members"], 1);
+
+        let (status, room) = request(&storage, &peers, &bus, &settings, Method::GET, "/admin/rooms/room_a", "secret", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(room["participants"][0]["peer_id"], "host");
+        assert_eq!(room["participants"][0]["role"], "host");
+        assert_eq!(room["participants"][1]["addr"], "127.0.0.1:8080");
+        assert_eq!(room["participants"][1]["node_id"], "node_a");
+
+        let (status, delivered) = request(&storage, &peers, &bus, &settings, Method::POST, "/admin/rooms/room_a/notice", "secret", r#"{"message":"maintenance at noon"}"#).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(delivered["delivered"], 2);
+        let notice: Value = serde_json::from_str(&next_msg(&mut guest_rx).to_string()).unwrap();
+        assert_eq!(notice["data_type"], "notice");
+        assert_eq!(notice["message"], "maintenance at noon");
+        next_msg(&mut host_rx);
+
+        let (status, _) = request(&storage, &peers, &bus, &settings, Method::DELETE, "/admin/peers/guest", "secret", "").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(matches!(next_msg(&mut guest_rx), Message::Close(Some(frame)) if frame.code == CloseCode::Policy));
+        assert!(!peers.read().await.contains_key("guest"));
+        let left: Value = serde_json::from_str(&next_msg(&mut host_rx).to_string()).unwrap();
+        assert_eq!(left["data_type"], "peer_left");
+
+        let (status, _) = request(&storage, &peers, &bus, &settings, Method::DELETE, "/admin/rooms/room_b", "secret", "").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let closed: Value = serde_json::from_str(&next_msg(&mut host_rx).to_string()).unwrap();
+        assert_eq!(closed["data_type"], "room_closed");
+        assert!(storage.room("room_b").await.unwrap().is_none());
+
+        let (status, _) = request(&storage, &peers, &bus, &settings, Method::GET, "/admin/rooms/room_b", "secret", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = request(&storage, &peers, &bus, &settings, Method::DELETE, "/admin/peers/guest", "secret", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let disabled: Settings = Settings::default();
+        let (status, _) = request(&storage, &peers, &bus, &disabled, Method::GET, "/admin/rooms", "secret", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_admin_kick_remote() {
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let hub: LocalHub = LocalHub::new();
+        let peers_a: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let peers_b: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let bus_a: SharedBus = Arc::new(hub.join(String::from("node_a")));
+        let bus_b: SharedBus = Arc::new(hub.join(String::from("node_b")));
+        for (peers, bus) in [(&peers_a, &bus_a), (&peers_b, &bus_b)] {
+            spawn(bus::forward(bus.subscribe().await.unwrap(), storage.clone(), peers.clone(), bus.clone()));
+        }
+        let settings: Settings = Settings { admin_token: Some(String::from("secret")), ..Settings::default() };
+
+        let mut host_rx = test_peer(&storage, &peers_a, &bus_a, "host").await;
+        let mut guest_rx = test_peer(&storage, &peers_b, &bus_b, "guest").await;
+        DataType::store_room(storage.clone(), String::from("room_a"), String::from("host"), RoomPolicy::default(), RoomAccess::default()).await.unwrap();
+        DataType::join_call(storage.clone(), String::from("room_a"), peers_b.clone(), bus_b.clone(), String::from("guest"), None, None).await.unwrap();
+        assert_eq!(recv_value(&mut host_rx).await["data_type"], "peer_joined");
+        while guest_rx.try_recv().is_ok() {}
+
+        // Asked on node_a, the guest connected to node_b is closed by node_b.
+        let (status, _) = request(&storage, &peers_a, &bus_a, &settings, Method::DELETE, "/admin/peers/guest", "secret", "").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(matches!(guest_rx.recv().await, Some(Message::Close(Some(frame))) if frame.code == CloseCode::Policy));
+        assert_eq!(recv_value(&mut host_rx).await["data_type"], "peer_left");
+        assert!(!peers_b.read().await.contains_key("guest"));
+        assert!(storage.peer("guest").await.unwrap().is_none());
+
+        let (status, _) = request(&storage, &peers_a, &bus_a, &settings, Method::DELETE, "/admin/peers/guest", "secret", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
\ No newline at end of file
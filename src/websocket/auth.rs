@@ -0,0 +1,281 @@
+use async_trait::async_trait;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use hyper::{
+    Body,
+    Request,
+    header::{HeaderMap, COOKIE, SEC_WEBSOCKET_PROTOCOL}
+};
+use serde_json::Value;
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH}
+};
+
+/// Name of the query parameter and cookie a token can be passed in.
+pub const TOKEN_PARAM: &str = "access_token";
+
+/// Subprotocol a browser offers, followed by the token itself, when it passes the token
+/// through `Sec-WebSocket-Protocol`, e.g. `new WebSocket(url, ["bearer", token])`.
+pub const BEARER_PROTOCOL: &str = "bearer";
+
+/// Who a connection belongs to, as established by an [`Authenticator`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub user_id: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthError {
+    MissingToken,
+    Malformed(String),
+    UnsupportedAlgorithm(String),
+    InvalidSignature,
+    Expired,
+    NotYetValid,
+    InvalidClaim(&'static str),
+    UnknownToken,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "No access token was supplied!"),
+            AuthError::Malformed(reason) => write!(f, "The access token is malformed: {}", reason),
+            AuthError::UnsupportedAlgorithm(alg) => write!(f, "The token algorithm {} is not supported!", alg),
+            AuthError::InvalidSignature => write!(f, "The token signature is invalid!"),
+            AuthError::Expired => write!(f, "The token has expired!"),
+            AuthError::NotYetValid => write!(f, "The token is not valid yet!"),
+            AuthError::InvalidClaim(claim) => write!(f, "The token claim {} is missing or invalid!", claim),
+            AuthError::UnknownToken => write!(f, "The access token is unknown!"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(&self, token: &str) -> Result<Identity, AuthError>;
+}
+
+/// Verifies HS256-signed JWTs. The `sub` claim becomes the user id; `exp` and `nbf` are
+/// enforced when present, `iss` and `aud` when configured.
+pub struct JwtAuthenticator {
+    secret: Vec<u8>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl JwtAuthenticator {
+    pub fn new(secret: &[u8], issuer: Option<String>, audience: Option<String>) -> Self {
+        JwtAuthenticator {
+            secret: secret.to_vec(),
+            issuer,
+            audience,
+        }
+    }
+
+    fn verify(&self, token: &str, now: u64) -> Result<Identity, AuthError> {
+        let mut parts = token.split('.');
+        let (header, payload, signature) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(header), Some(payload), Some(signature), None) => (header, payload, signature),
+            _ => return Err(AuthError::Malformed(String::from("expected three dot-separated parts"))),
+        };
+        let signing_input: &str = &token[..header.len() + 1 + payload.len()];
+
+        let header: Value = decode_part(header)?;
+        match header.get("alg").and_then(Value::as_str) {
+            Some("HS256") => {},
+            Some(alg) => return Err(AuthError::UnsupportedAlgorithm(alg.to_string())),
+            None => return Err(AuthError::Malformed(String::from("missing alg"))),
+        }
+
+        let signature: Vec<u8> = URL_SAFE_NO_PAD.decode(signature).map_err(|e| AuthError::Malformed(e.to_string()))?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length!");
+        mac.update(signing_input.as_bytes());
+        mac.verify_slice(&signature).map_err(|_| AuthError::InvalidSignature)?;
+
+        let claims: Value = decode_part(payload)?;
+        if let Some(exp) = claims.get("exp") {
+            if exp.as_u64().ok_or(AuthError::InvalidClaim("exp"))? <= now {
+                return Err(AuthError::Expired);
+            }
+        }
+        if let Some(nbf) = claims.get("nbf") {
+            if nbf.as_u64().ok_or(AuthError::InvalidClaim("nbf"))? > now {
+                return Err(AuthError::NotYetValid);
+            }
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer.as_str()) {
+                return Err(AuthError::InvalidClaim("iss"));
+            }
+        }
+        if let Some(audience) = &self.audience {
+            let matches: bool = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience.as_str())),
+                _ => false,
+            };
+            if !matches {
+                return Err(AuthError::InvalidClaim("aud"));
+            }
+        }
+
+        match claims.get("sub").and_then(Value::as_str) {
+            Some(sub) if !sub.is_empty() => Ok(Identity { user_id: sub.to_string() }),
+            _ => Err(AuthError::InvalidClaim("sub")),
+        }
+    }
+}
+
+#[async_trait]
+impl Authenticator for JwtAuthenticator {
+    async fn authenticate(&self, token: &str) -> Result<Identity, AuthError> {
+        let now: u64 = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        self.verify(token, now)
+    }
+}
+
+/// Accepts a fixed set of tokens, each mapped to a user id. Meant for tests and local setups.
+pub struct StaticTokenAuthenticator {
+    tokens: HashMap<String, Identity>,
+}
+
+impl StaticTokenAuthenticator {
+    pub fn new(tokens: HashMap<String, String>) -> Self {
+        StaticTokenAuthenticator {
+            tokens: tokens.into_iter().map(|(token, user_id)| (token, Identity { user_id })).collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticTokenAuthenticator {
+    async fn authenticate(&self, token: &str) -> Result<Identity, AuthError> {
+        self.tokens.get(token).cloned().ok_or(AuthError::UnknownToken)
+    }
+}
+
+/// Where a token was found on the upgrade request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenSource {
+    Query,
+    Protocol,
+    Cookie,
+}
+
+/// Looks for a bearer token in the `access_token` query parameter, the
+/// `Sec-WebSocket-Protocol` header (after a `bearer` entry) or the `access_token` cookie.
+pub fn extract_token(req: &Request<Body>) -> Option<(String, TokenSource)> {
+    let from_query = req.uri().query().and_then(|query| {
+        query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == TOKEN_PARAM)
+        .map(|(_, token)| token.to_string())
+    });
+    if let Some(token) = from_query {
+        return Some((token, TokenSource::Query));
+    }
+
+    if let Some(token) = protocol_token(req.headers()) {
+        return Some((token, TokenSource::Protocol));
+    }
+
+    req.headers().get_all(COOKIE).iter()
+    .filter_map(|cookie| cookie.to_str().ok())
+    .flat_map(|cookie| cookie.split(';'))
+    .filter_map(|pair| pair.trim().split_once('='))
+    .find(|(name, _)| *name == TOKEN_PARAM)
+    .map(|(_, token)| (token.to_string(), TokenSource::Cookie))
+}
+
+fn protocol_token(headers: &HeaderMap) -> Option<String> {
+    let protocols: Vec<&str> = headers.get_all(SEC_WEBSOCKET_PROTOCOL).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    protocols.iter()
+    .position(|protocol| protocol.eq_ignore_ascii_case(BEARER_PROTOCOL))
+    .and_then(|index| protocols.get(index + 1))
+    .map(|token| token.to_string())
+}
+
+fn decode_part(part: &str) -> Result<Value, AuthError> {
+    let bytes: Vec<u8> = URL_SAFE_NO_PAD.decode(part).map_err(|e| AuthError::Malformed(e.to_string()))?;
+    serde_json::from_slice(&bytes).map_err(|e| AuthError::Malformed(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const SECRET: &[u8] = b"test-secret";
+
+    fn sign(header: Value, claims: Value, secret: &[u8]) -> String {
+        let signing_input: String = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(signing_input.as_bytes());
+        format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()))
+    }
+
+    #[test]
+    fn test_jwt_authenticator() {
+        let hs256: Value = json!({"alg": "HS256", "typ": "JWT"});
+        let jwt: JwtAuthenticator = JwtAuthenticator::new(SECRET, Some(String::from("issuer")), Some(String::from("signaling")));
+        let claims: Value = json!({"sub": "alice", "iss": "issuer", "aud": ["signaling"], "exp": 2000, "nbf": 1000});
+
+        let token: String = sign(hs256.clone(), claims.clone(), SECRET);
+        assert_eq!(jwt.verify(&token, 1500), Ok(Identity { user_id: String::from("alice") }));
+        assert_eq!(jwt.verify(&token, 2000), Err(AuthError::Expired));
+        assert_eq!(jwt.verify(&token, 999), Err(AuthError::NotYetValid));
+
+        let forged: String = sign(hs256.clone(), claims.clone(), b"other-secret");
+        assert_eq!(jwt.verify(&forged, 1500), Err(AuthError::InvalidSignature));
+
+        let none_alg: String = sign(json!({"alg": "none"}), claims, SECRET);
+        assert_eq!(jwt.verify(&none_alg, 1500), Err(AuthError::UnsupportedAlgorithm(String::from("none"))));
+
+        let wrong_audience: String = sign(hs256.clone(), json!({"sub": "alice", "iss": "issuer", "aud": "other"}), SECRET);
+        assert_eq!(jwt.verify(&wrong_audience, 1500), Err(AuthError::InvalidClaim("aud")));
+
+        let no_subject: String = sign(hs256, json!({"iss": "issuer", "aud": "signaling"}), SECRET);
+        assert_eq!(jwt.verify(&no_subject, 1500), Err(AuthError::InvalidClaim("sub")));
+
+        assert!(matches!(jwt.verify("not-a-token", 1500), Err(AuthError::Malformed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_static_token_authenticator() {
+        let tokens: HashMap<String, String> = HashMap::from([(String::from("token-1"), String::from("bob"))]);
+        let auth: StaticTokenAuthenticator = StaticTokenAuthenticator::new(tokens);
+        assert_eq!(auth.authenticate("token-1").await, Ok(Identity { user_id: String::from("bob") }));
+        assert_eq!(auth.authenticate("token-2").await, Err(AuthError::UnknownToken));
+    }
+
+    #[test]
+    fn test_extract_token() {
+        let req: Request<Body> = Request::builder().uri("/ws?room=1&access_token=abc").body(Body::empty()).unwrap();
+        assert_eq!(extract_token(&req), Some((String::from("abc"), TokenSource::Query)));
+
+        let req: Request<Body> = Request::builder().uri("/ws").header("Sec-WebSocket-Protocol", "bearer, def").body(Body::empty()).unwrap();
+        assert_eq!(extract_token(&req), Some((String::from("def"), TokenSource::Protocol)));
+
+        let req: Request<Body> = Request::builder().uri("/ws").header("Cookie", "theme=dark; access_token=ghi").body(Body::empty()).unwrap();
+        assert_eq!(extract_token(&req), Some((String::from("ghi"), TokenSource::Cookie)));
+
+        let req: Request<Body> = Request::builder().uri("/ws").body(Body::empty()).unwrap();
+        assert_eq!(extract_token(&req), None);
+    }
+}
\ No newline at end of file
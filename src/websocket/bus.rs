@@ -0,0 +1,252 @@
+pub mod local;
+pub mod redis;
+
+use async_trait::async_trait;
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::sleep;
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, Message};
+use tracing::{debug, warn};
+
+use crate::websocket::bus::local::LocalHub;
+use crate::websocket::bus::redis::RedisBus;
+use crate::websocket::data_transfer::{send_to, DataTransfer, DataType};
+
+use super::NodeId;
+use super::PeerId;
+use super::PeerMap;
+use super::SharedBus;
+use super::SharedStorage;
+
+/// Messages buffered per node before publishing to it fails.
+const BUS_QUEUE_SIZE: usize = 1024;
+
+/// How long a node has to be back on the bus before its peers are reaped. Covers a
+/// subscription that is being re-established.
+pub const REAP_GRACE: Duration = Duration::from_secs(5);
+
+/// Nodes whose peers are being reaped, so relaying to one of them while it is reaped
+/// does not start another pass.
+static REAPING: Mutex<Vec<NodeId>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BusError {
+    Unsupported(String),
+    Backend(String),
+    /// No node with this id is listening.
+    Unreachable(NodeId),
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BusError::Unsupported(scheme) => write!(f, "Unsupported bus {:?}, expected memory or redis://", scheme),
+            BusError::Backend(reason) => write!(f, "The message bus failed: {}", reason),
+            BusError::Unreachable(node_id) => write!(f, "Node {} is not reachable", node_id),
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+/// What one server instance asks of another.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BusMessage {
+    /// A text frame for the listed peers, all connected to the receiving node.
+    Deliver {
+        targets: Vec<PeerId>,
+        payload: String,
+    },
+    /// A close frame for the listed peers, which the receiving node then closes as if
+    /// they had hung up.
+    Close {
+        targets: Vec<PeerId>,
+        code: u16,
+        reason: String,
+    },
+}
+
+impl BusMessage {
+    /// What carries `msg` to `targets`, for the frames that travel: text and close frames.
+    fn wrap(msg: &Message, targets: Vec<PeerId>) -> Option<BusMessage> {
+        match msg {
+            Message::Text(payload) => Some(BusMessage::Deliver { targets, payload: payload.clone() }),
+            Message::Close(Some(frame)) => Some(BusMessage::Close { targets, code: frame.code.into(), reason: frame.reason.to_string() }),
+            _ => None,
+        }
+    }
+}
+
+/// Carries signaling messages between server instances, so peers connected to
+/// different nodes behind a load balancer can reach each other. Which node a peer is on
+/// comes from its [`PeerRecord`](crate::websocket::store::PeerRecord) in the shared storage.
+#[async_trait]
+pub trait Bus: Send + Sync {
+    /// This node's id, unique across the cluster.
+    fn node_id(&self) -> &str;
+    /// Sends `msg` to the node `node_id`. Delivery is best effort; a message for a node
+    /// that is down is lost.
+    async fn publish(&self, node_id: &str, msg: &BusMessage) -> Result<(), BusError>;
+    /// The messages published to this node. Called once, by [`forward`].
+    async fn subscribe(&self) -> Result<Receiver<BusMessage>, BusError>;
+    /// Whether the node `node_id` is subscribed to the bus.
+    async fn is_listening(&self, node_id: &str) -> Result<bool, BusError>;
+}
+
+/// Opens the bus behind `bus_url` as node `node_id`: `memory` for a single instance, or
+/// `redis://host:6379/0` for Redis pub/sub.
+pub async fn open(url: &str, node_id: NodeId) -> Result<SharedBus, BusError> {
+    match url.split_once("://") {
+        None if url == "memory" => Ok(Arc::new(LocalHub::new().join(node_id))),
+        Some(("redis", _)) => Ok(Arc::new(RedisBus::connect(url, node_id).await?)),
+        Some((scheme, _)) => Err(BusError::Unsupported(scheme.to_string())),
+        None => Err(BusError::Unsupported(url.to_string())),
+    }
+}
+
+/// Hands `msg` to the nodes the `targets` are connected to, looked up in `storage`.
+/// Targets no longer registered anywhere are skipped. Only text and close frames travel.
+/// Returns the nodes that were not listening, whose peers the caller should [`reap`].
+pub async fn relay(storage: &SharedStorage, bus: &SharedBus, targets: &[PeerId], msg: &Message) -> Vec<NodeId> {
+    let mut unreachable: Vec<NodeId> = Vec::new();
+    if !matches!(msg, Message::Text(_) | Message::Close(Some(_))) {
+        return unreachable;
+    }
+    let mut nodes: Vec<(NodeId, Vec<PeerId>)> = Vec::new();
+    for peer_id in targets {
+        let node_id: NodeId = match storage.peer(peer_id).await {
+            Ok(Some(peer)) if peer.node_id != bus.node_id() => peer.node_id,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!(peer_id = %peer_id, error = %e, "failed to look up the peer's node");
+                continue;
+            }
+        };
+        match nodes.iter_mut().find(|(node, _)| *node == node_id) {
+            Some((_, peer_ids)) => peer_ids.push(peer_id.clone()),
+            None => nodes.push((node_id, vec![peer_id.clone()])),
+        }
+    }
+
+    for (node_id, targets) in nodes {
+        let recipients: usize = targets.len();
+        let relayed: BusMessage = BusMessage::wrap(msg, targets).expect("only frames that travel get this far");
+        match bus.publish(&node_id, &relayed).await {
+            Ok(()) => debug!(node_id = %node_id, recipients, "message relayed to another node"),
+            Err(BusError::Unreachable(node_id)) => {
+                warn!(node_id = %node_id, "failed to relay the message, the node is not listening");
+                unreachable.push(node_id);
+            },
+            Err(e) => warn!(node_id = %node_id, error = %e, "failed to relay the message"),
+        }
+    }
+    unreachable
+}
+
+/// Closes the peers registered to node `node_id` when it is not on the bus, and still
+/// is not after `grace`, as the node crashed without unregistering them. They leave
+/// their rooms as if they had hung up.
+pub async fn reap(storage: SharedStorage, peers: PeerMap, bus: SharedBus, node_id: NodeId, grace: Duration) {
+    if node_id == bus.node_id() || !is_gone(&bus, &node_id).await {
+        return;
+    }
+    sleep(grace).await;
+    if !is_gone(&bus, &node_id).await {
+        return;
+    }
+    {
+        let mut reaping = REAPING.lock().expect("reaping list poisoned");
+        if reaping.contains(&node_id) {
+            return;
+        }
+        reaping.push(node_id.clone());
+    }
+
+    match storage.peers_on(&node_id).await {
+        Ok(gone) if !gone.is_empty() => {
+            warn!(node_id = %node_id, peers = gone.len(), "reaping the peers of a node that is gone");
+            for peer_id in gone {
+                DataType::close(storage.clone(), peers.clone(), bus.clone(), peer_id).await;
+            }
+        },
+        Ok(_) => {},
+        Err(e) => warn!(node_id = %node_id, error = %e, "failed to list the peers of a node that is gone"),
+    }
+    REAPING.lock().expect("reaping list poisoned").retain(|reaped| *reaped != node_id);
+}
+
+/// [`reap`]s every other node with peers in `storage`, for the nodes that went down or
+/// were restarted while this one was not running.
+pub async fn reap_gone(storage: SharedStorage, peers: PeerMap, bus: SharedBus, grace: Duration) {
+    let nodes: Vec<NodeId> = match storage.nodes().await {
+        Ok(nodes) => nodes,
+        Err(e) => {
+            warn!(error = %e, "failed to list the nodes with peers");
+            return;
+        }
+    };
+    join_all(nodes.into_iter().map(|node_id| reap(storage.clone(), peers.clone(), bus.clone(), node_id, grace))).await;
+}
+
+async fn is_gone(bus: &SharedBus, node_id: &str) -> bool {
+    match bus.is_listening(node_id).await {
+        Ok(listening) => !listening,
+        Err(e) => {
+            warn!(node_id = %node_id, error = %e, "failed to check whether the node is listening");
+            false
+        }
+    }
+}
+
+/// Delivers what other nodes publish for the peers connected here. Runs until the bus
+/// subscription ends.
+pub async fn forward(mut rx: Receiver<BusMessage>, storage: SharedStorage, peers: PeerMap, bus: SharedBus) {
+    while let Some(msg) = rx.recv().await {
+        match msg {
+            BusMessage::Deliver { targets, payload } => {
+                for dead_peer in send_to(peers.clone(), &targets, Message::Text(payload)).await {
+                    DataType::close(storage.clone(), peers.clone(), bus.clone(), dead_peer).await;
+                }
+            },
+            BusMessage::Close { targets, code, reason } => {
+                let frame: CloseFrame = CloseFrame { code: CloseCode::from(code), reason: reason.into() };
+                // Queued ahead of dropping the peers, so their writers still flush it.
+                send_to(peers.clone(), &targets, Message::Close(Some(frame))).await;
+                for peer_id in targets {
+                    DataType::close(storage.clone(), peers.clone(), bus.clone(), peer_id).await;
+                }
+            },
+        }
+    }
+    warn!("the message bus subscription ended");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_open() {
+        assert_eq!(open("memory", String::from("node_a")).await.unwrap().node_id(), "node_a");
+        assert!(matches!(open("nats://localhost", String::from("node_a")).await, Err(BusError::Unsupported(_))));
+    }
+
+    #[test]
+    fn test_bus_message() {
+        let msg: BusMessage = BusMessage::Deliver { targets: vec![String::from("peer_a")], payload: String::from("{}") };
+        let json: String = serde_json::to_string(&msg).unwrap();
+        assert_eq!(json, r#"{"kind":"deliver","targets":["peer_a"],"payload":"{}"}"#);
+        assert_eq!(serde_json::from_str::<BusMessage>(&json).unwrap(), msg);
+
+        let frame: Message = Message::Close(Some(CloseFrame { code: CloseCode::Policy, reason: "bye".into() }));
+        let msg: BusMessage = BusMessage::wrap(&frame, vec![String::from("peer_a")]).unwrap();
+        let json: String = serde_json::to_string(&msg).unwrap();
+        assert_eq!(json, r#"{"kind":"close","targets":["peer_a"],"code":1008,"reason":"bye"}"#);
+        assert_eq!(BusMessage::wrap(&Message::Ping(Vec::new()), vec![String::from("peer_a")]), None);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,88 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+
+use crate::websocket::bus::{Bus, BusError, BusMessage, BUS_QUEUE_SIZE};
+
+use super::NodeId;
+
+/// Connects [`LocalBus`] nodes living in one process, as the tests run a cluster.
+#[derive(Clone, Default)]
+pub struct LocalHub {
+    nodes: Arc<Mutex<HashMap<NodeId, Sender<BusMessage>>>>,
+}
+
+impl LocalHub {
+    pub fn new() -> Self {
+        LocalHub::default()
+    }
+
+    /// Adds a node to the hub. A node joining again under the same id replaces the first.
+    pub fn join(&self, node_id: NodeId) -> LocalBus {
+        let (tx, rx): (Sender<BusMessage>, Receiver<BusMessage>) = channel(BUS_QUEUE_SIZE);
+        self.nodes.lock().expect("bus hub poisoned").insert(node_id.clone(), tx);
+        LocalBus {
+            node_id,
+            hub: self.clone(),
+            rx: Mutex::new(Some(rx)),
+        }
+    }
+}
+
+/// A node on a [`LocalHub`]. On a hub of its own it is the bus of a single instance.
+pub struct LocalBus {
+    node_id: NodeId,
+    hub: LocalHub,
+    rx: Mutex<Option<Receiver<BusMessage>>>,
+}
+
+#[async_trait]
+impl Bus for LocalBus {
+    fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    async fn publish(&self, node_id: &str, msg: &BusMessage) -> Result<(), BusError> {
+        let tx: Sender<BusMessage> = self.hub.nodes.lock().expect("bus hub poisoned").get(node_id).cloned()
+            .ok_or_else(|| BusError::Unreachable(node_id.to_string()))?;
+        match tx.try_send(msg.clone()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(BusError::Backend(format!("the queue of node {} is full", node_id))),
+            Err(TrySendError::Closed(_)) => Err(BusError::Unreachable(node_id.to_string())),
+        }
+    }
+
+    async fn subscribe(&self) -> Result<Receiver<BusMessage>, BusError> {
+        self.rx.lock().expect("bus receiver poisoned").take()
+            .ok_or_else(|| BusError::Backend(String::from("the bus is already subscribed to")))
+    }
+
+    async fn is_listening(&self, node_id: &str) -> Result<bool, BusError> {
+        Ok(self.hub.nodes.lock().expect("bus hub poisoned").get(node_id).is_some_and(|tx| !tx.is_closed()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_local_bus() {
+        let hub: LocalHub = LocalHub::new();
+        let node_a: LocalBus = hub.join(String::from("node_a"));
+        let node_b: LocalBus = hub.join(String::from("node_b"));
+        let mut rx: Receiver<BusMessage> = node_b.subscribe().await.unwrap();
+        assert!(node_b.subscribe().await.is_err());
+
+        let msg: BusMessage = BusMessage::Deliver { targets: vec![String::from("peer_b")], payload: String::from("{}") };
+        node_a.publish("node_b", &msg).await.unwrap();
+        assert_eq!(rx.recv().await, Some(msg.clone()));
+        assert_eq!(node_a.publish("node_c", &msg).await, Err(BusError::Unreachable(String::from("node_c"))));
+
+        assert!(node_a.is_listening("node_b").await.unwrap());
+        assert!(!node_a.is_listening("node_c").await.unwrap());
+        drop(rx);
+        assert!(!node_a.is_listening("node_b").await.unwrap());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,148 @@
+use ::redis::{aio::{ConnectionManager, PubSub}, AsyncCommands, Client, Msg, RedisError};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use std::time::Duration;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::{spawn, time::sleep};
+use tracing::{info, warn};
+
+use crate::websocket::bus::{Bus, BusError, BusMessage, BUS_QUEUE_SIZE};
+
+use super::NodeId;
+
+/// Every channel this bus uses starts with this prefix.
+const CHANNEL_PREFIX: &str = "signaling:node:";
+
+/// Wait before subscribing again after the subscription connection dropped.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Redis pub/sub with a channel per node. Publishing goes through a shared connection;
+/// each node holds one more connection for its subscription, which is re-established
+/// when it drops. Messages published meanwhile are lost, as pub/sub does not store them.
+pub struct RedisBus {
+    node_id: NodeId,
+    client: Client,
+    conn: ConnectionManager,
+}
+
+impl RedisBus {
+    /// Connects to `url`, e.g. `redis://:password@localhost:6379/0`, as node `node_id`.
+    pub async fn connect(url: &str, node_id: NodeId) -> Result<RedisBus, BusError> {
+        let client: Client = Client::open(url)?;
+        let conn: ConnectionManager = ConnectionManager::new(client.clone()).await?;
+        Ok(RedisBus { node_id, client, conn })
+    }
+}
+
+#[async_trait]
+impl Bus for RedisBus {
+    fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    async fn publish(&self, node_id: &str, msg: &BusMessage) -> Result<(), BusError> {
+        let payload: String = serde_json::to_string(msg).expect("Failed to serialize!");
+        let receivers: u64 = self.conn.clone().publish(channel_name(node_id), payload).await?;
+        if receivers == 0 {
+            return Err(BusError::Unreachable(node_id.to_string()));
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<Receiver<BusMessage>, BusError> {
+        let pubsub: PubSub = subscription(&self.client, &self.node_id).await?;
+        let (tx, rx): (Sender<BusMessage>, Receiver<BusMessage>) = channel(BUS_QUEUE_SIZE);
+        spawn(receive(self.client.clone(), self.node_id.clone(), pubsub, tx));
+        Ok(rx)
+    }
+
+    async fn is_listening(&self, node_id: &str) -> Result<bool, BusError> {
+        let (_, subscribers): (String, u64) = ::redis::cmd("PUBSUB").arg("NUMSUB").arg(channel_name(node_id))
+            .query_async(&mut self.conn.clone())
+            .await?;
+        Ok(subscribers > 0)
+    }
+}
+
+impl From<RedisError> for BusError {
+    fn from(e: RedisError) -> Self {
+        BusError::Backend(e.to_string())
+    }
+}
+
+fn channel_name(node_id: &str) -> String {
+    format!("{}{}", CHANNEL_PREFIX, node_id)
+}
+
+async fn subscription(client: &Client, node_id: &str) -> Result<PubSub, BusError> {
+    let mut pubsub: PubSub = client.get_async_connection().await?.into_pubsub();
+    pubsub.subscribe(channel_name(node_id)).await?;
+    Ok(pubsub)
+}
+
+/// Passes published messages on to `tx` until it closes, subscribing again whenever
+/// the connection drops.
+async fn receive(client: Client, node_id: NodeId, mut pubsub: PubSub, tx: Sender<BusMessage>) {
+    loop {
+        let mut messages = pubsub.on_message();
+        while let Some(msg) = messages.next().await {
+            let msg: BusMessage = match parse(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!(error = %e, "ignoring a malformed bus message");
+                    continue;
+                }
+            };
+            if tx.send(msg).await.is_err() {
+                return;
+            }
+        }
+        drop(messages);
+
+        warn!(node_id = %node_id, "lost the bus subscription, subscribing again");
+        pubsub = loop {
+            sleep(RESUBSCRIBE_DELAY).await;
+            if tx.is_closed() {
+                return;
+            }
+            match subscription(&client, &node_id).await {
+                Ok(pubsub) => break pubsub,
+                Err(e) => warn!(node_id = %node_id, error = %e, "failed to subscribe to the bus"),
+            }
+        };
+        info!(node_id = %node_id, "bus subscription restored");
+    }
+}
+
+fn parse(msg: &Msg) -> Result<BusMessage, BusError> {
+    let payload: String = msg.get_payload()?;
+    serde_json::from_str(&payload).map_err(|e| BusError::Backend(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_name() {
+        assert_eq!(channel_name("node_a"), "signaling:node:node_a");
+    }
+
+    /// Runs against the scratch database in `WS_SERVER_TEST_REDIS_URL`, skipped when unset.
+    #[tokio::test]
+    async fn test_redis_bus() {
+        let Ok(url) = std::env::var("WS_SERVER_TEST_REDIS_URL") else {
+            return;
+        };
+        let node_a: RedisBus = RedisBus::connect(&url, uuid::Uuid::new_v4().to_string()).await.unwrap();
+        let node_b: RedisBus = RedisBus::connect(&url, uuid::Uuid::new_v4().to_string()).await.unwrap();
+        let mut rx: Receiver<BusMessage> = node_b.subscribe().await.unwrap();
+
+        let msg: BusMessage = BusMessage::Deliver { targets: vec![String::from("peer_b")], payload: String::from("{}") };
+        node_a.publish(node_b.node_id(), &msg).await.unwrap();
+        assert_eq!(rx.recv().await, Some(msg.clone()));
+        assert!(matches!(node_a.publish("nobody", &msg).await, Err(BusError::Unreachable(_))));
+        assert!(node_a.is_listening(node_b.node_id()).await.unwrap());
+        assert!(!node_a.is_listening("nobody").await.unwrap());
+    }
+}
\ No newline at end of file
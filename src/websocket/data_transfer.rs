@@ -1,170 +1,412 @@
 use async_trait::async_trait;
-use futures_util::SinkExt;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
 use std::collections::HashMap;
-use std::sync::Arc;
-use std::net::SocketAddr;
-use tokio::sync::{MutexGuard, Mutex};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+use subtle::ConstantTimeEq;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::{spawn, spawn_blocking};
 use tokio_tungstenite::tungstenite::protocol::Message;
+use tracing::{debug, error, info, trace, warn};
+use uuid::Uuid;
 
-use super::ChatRoom;
-use super::ChatRooms;
+use crate::error::Error;
+use crate::logging::redact;
+use crate::metrics::{data_type_of, timed_lock, Lock, METRICS};
+use crate::websocket::bus;
+use crate::websocket::store::{update_room, PeerRecord, Versioned};
+
+use super::Peer;
+use super::PeerId;
 use super::PeerMap;
+use super::RoomId;
+use super::SharedBus;
+use super::SharedStorage;
 
 pub struct DataType;
 
 #[async_trait]
 pub trait DataTransfer {
-    async fn store_room(rooms: ChatRooms, room: Option<ChatRoom>, data: StoreRoom, addr: SocketAddr);
-    async fn store_offer(rooms: ChatRooms, room: Option<ChatRoom>, data: StoreRoom);
-    async fn store_candidate(rooms: ChatRooms, room: Option<ChatRoom>, data: StoreRoom);
-    async fn send_answer(room: Option<ChatRoom>, data: StoreRoom, peers: PeerMap);
-    async fn send_candidate(room: Option<ChatRoom>, data: StoreRoom, peers: PeerMap);
-    async fn join_call(room: Option<ChatRoom>, peers: PeerMap);
-    async fn close(rooms: ChatRooms, peers: PeerMap, addr: SocketAddr);
+    async fn store_room(storage: SharedStorage, room_id: RoomId, peer_id: PeerId, policy: RoomPolicy, access: RoomAccess) -> Result<(), Error>;
+    async fn store_offer(storage: SharedStorage, room_id: RoomId, offer: Offer, peer_id: PeerId) -> Result<(), Error>;
+    async fn store_candidate(storage: SharedStorage, room_id: RoomId, candidate: Candidate, peer_id: PeerId) -> Result<(), Error>;
+    async fn send_offer(storage: SharedStorage, room_id: RoomId, offer: Offer, to: Option<PeerId>, peers: PeerMap, bus: SharedBus, peer_id: PeerId) -> Result<(), Error>;
+    async fn send_answer(storage: SharedStorage, room_id: RoomId, answer: Answer, to: Option<PeerId>, peers: PeerMap, bus: SharedBus, peer_id: PeerId) -> Result<(), Error>;
+    async fn send_candidate(storage: SharedStorage, room_id: RoomId, candidate: Candidate, to: Option<PeerId>, peers: PeerMap, bus: SharedBus, peer_id: PeerId) -> Result<(), Error>;
+    async fn join_call(storage: SharedStorage, room_id: RoomId, peers: PeerMap, bus: SharedBus, peer_id: PeerId, password: Option<String>, invite_token: Option<String>) -> Result<(), Error>;
+    async fn create_invite(storage: SharedStorage, room_id: RoomId, role: Option<Role>, peers: PeerMap, bus: SharedBus, peer_id: PeerId) -> Result<(), Error>;
+    async fn kick(storage: SharedStorage, room_id: RoomId, target: PeerId, peers: PeerMap, bus: SharedBus, peer_id: PeerId) -> Result<(), Error>;
+    async fn set_role(storage: SharedStorage, room_id: RoomId, target: PeerId, role: Role, peers: PeerMap, bus: SharedBus, peer_id: PeerId) -> Result<(), Error>;
+    async fn close(storage: SharedStorage, peers: PeerMap, bus: SharedBus, peer_id: PeerId);
 }
 
 #[async_trait]
 impl DataTransfer for DataType {
-    async fn store_room(rooms: ChatRooms, room: Option<ChatRoom>, data: StoreRoom, addr: SocketAddr) {
-        match room {
-            None => {
-                let new_room: Room = Room { 
-                    room_id: data.room_id.clone(),
-                    offer: Offer { 
-                        r#type: String::new(), 
-                        sdp: String::new(), 
-                    },
-                    candidates: Vec::new(),
-                };
-                rooms.lock().await.insert(addr, Arc::new(Mutex::new(new_room.clone())));
-                println!("= store_room = rooms: {:?}", rooms)
-            },
-            Some(exist_room) => {
-                eprintln!("= store_room = {} exist!", exist_room.lock().await.room_id.clone())
-            },
+    async fn store_room(storage: SharedStorage, room_id: RoomId, peer_id: PeerId, policy: RoomPolicy, access: RoomAccess) -> Result<(), Error> {
+        let mut new_room: Room = Room::new(room_id.clone(), peer_id, policy);
+        new_room.access = access;
+        if !storage.insert_room(&new_room).await? {
+            return Err(Error::RoomExists(room_id));
         }
+        debug!(policy = ?policy, "room created");
+        Ok(())
     }
 
-    async fn store_offer(rooms: ChatRooms, room: Option<ChatRoom>, data: StoreRoom) {
-        match room {
-            Some(exist_room) => {
-                exist_room.lock().await.offer = data.offer;
-                println!("= store_offer = rooms: {:?}", rooms);
-            },
-            None => eprintln!("= store_offer = The room do not exist!"),
-        }
+    async fn store_offer(storage: SharedStorage, room_id: RoomId, offer: Offer, peer_id: PeerId) -> Result<(), Error> {
+        update_room(&storage, &room_id, |room| {
+            room.require(&peer_id, Role::can_publish, "publish offers")?;
+            if let Some(participant) = room.participant_mut(&peer_id) {
+                participant.offer = Some(offer.clone());
+            }
+            Ok(())
+        }).await?;
+        debug!(offer = ?offer, "offer stored");
+        Ok(())
     }
 
-    async fn store_candidate(rooms: ChatRooms, room: Option<ChatRoom>, data: StoreRoom) {
-        match room {
-            Some(exist_room) => {
-                exist_room.lock().await.candidates.push(data.candidate);
-                println!("= store_candidate = rooms: {:?}", rooms);
+    async fn store_candidate(storage: SharedStorage, room_id: RoomId, candidate: Candidate, peer_id: PeerId) -> Result<(), Error> {
+        update_room(&storage, &room_id, |room| match room.participant_mut(&peer_id) {
+            Some(participant) => {
+                participant.candidates.push(candidate.clone());
+                Ok(())
             },
-            None => eprintln!("= store_candidate = The room do not exist!"),
-        }
+            None => Err(Error::NotInRoom(peer_id.clone())),
+        }).await?;
+        debug!(candidate = ?candidate, "candidate stored");
+        Ok(())
     }
 
-    async fn send_answer(room: Option<ChatRoom>, data: StoreRoom, peers: PeerMap) {
-        match room {
-            Some(_) => {
-                let answer_data: Value = json!({
-                    "data_type": "answer",
-                    "answer": data.answer
-                });
-                let answer_data_string: String = serde_json::to_string(&answer_data).expect("Failed to serialize!");
-                send_to_all(peers.clone(), Message::Text(answer_data_string.clone())).await;
-                
-                println!("= send_answer = answer_data: {}", answer_data_string.clone());
-            },
-            None => eprintln!("= send_answer = The room do not exist!"),
-        }
+    async fn send_offer(storage: SharedStorage, room_id: RoomId, offer: Offer, to: Option<PeerId>, peers: PeerMap, bus: SharedBus, peer_id: PeerId) -> Result<(), Error> {
+        let exist_room: Room = find_room(&storage, &room_id).await?;
+        exist_room.require(&peer_id, Role::can_publish, "publish offers")?;
+        let targets: Vec<PeerId> = exist_room.recipients(&peer_id, &to)?;
+        let offer_data: Value = json!({
+            "data_type": "offer",
+            "from": peer_id,
+            "offer": offer
+        });
+        let offer_data_string: String = serde_json::to_string(&offer_data).expect("Failed to serialize!");
+        deliver(storage.clone(), peers.clone(), bus.clone(), &targets, Message::Text(offer_data_string)).await;
+        debug!(recipients = targets.len(), offer = ?offer, "offer relayed");
+        Ok(())
+    }
+
+    async fn send_answer(storage: SharedStorage, room_id: RoomId, answer: Answer, to: Option<PeerId>, peers: PeerMap, bus: SharedBus, peer_id: PeerId) -> Result<(), Error> {
+        let targets: Vec<PeerId> = find_room(&storage, &room_id).await?.recipients(&peer_id, &to)?;
+        let answer_data: Value = json!({
+            "data_type": "answer",
+            "from": peer_id,
+            "answer": answer
+        });
+        let answer_data_string: String = serde_json::to_string(&answer_data).expect("Failed to serialize!");
+        deliver(storage.clone(), peers.clone(), bus.clone(), &targets, Message::Text(answer_data_string)).await;
+        debug!(recipients = targets.len(), answer = ?answer, "answer relayed");
+        Ok(())
+    }
+
+    async fn send_candidate(storage: SharedStorage, room_id: RoomId, candidate: Candidate, to: Option<PeerId>, peers: PeerMap, bus: SharedBus, peer_id: PeerId) -> Result<(), Error> {
+        let targets: Vec<PeerId> = find_room(&storage, &room_id).await?.recipients(&peer_id, &to)?;
+        let candidate_data: Value = json!({
+            "data_type": "candidate",
+            "from": peer_id,
+            "candidate": candidate
+        });
+        let candidate_data_string: String = serde_json::to_string(&candidate_data).expect("Failed to serialize!");
+        deliver(storage.clone(), peers.clone(), bus.clone(), &targets, Message::Text(candidate_data_string)).await;
+        debug!(recipients = targets.len(), candidate = ?candidate, "candidate relayed");
+        Ok(())
     }
 
-    async fn send_candidate(room: Option<ChatRoom>, data: StoreRoom, peers: PeerMap) {
-        match room {
-            Some(_) => {
+    async fn join_call(storage: SharedStorage, room_id: RoomId, peers: PeerMap, bus: SharedBus, peer_id: PeerId, password: Option<String>, invite_token: Option<String>) -> Result<(), Error> {
+        let unlocked: bool = match password {
+            Some(password) => unlocks(&storage, &room_id, password).await?,
+            None => false,
+        };
+        let (role, existing): (Role, Vec<Participant>) = update_room(&storage, &room_id, |room| {
+            let role: Role = room.admit(&peer_id, unlocked, invite_token.as_deref())?;
+            let existing = room.participants.iter().filter(|participant| participant.peer_id != peer_id).cloned().collect();
+            Ok((role, existing))
+        }).await?;
+        let others: Vec<PeerId> = existing.iter().map(|participant| participant.peer_id.clone()).collect();
+
+        let members_data: Value = json!({
+            "data_type": "room_members",
+            "room_id": room_id,
+            "role": role,
+            "peers": others,
+        });
+        deliver(storage.clone(), peers.clone(), bus.clone(), std::slice::from_ref(&peer_id), Message::Text(members_data.to_string())).await;
+
+        for participant in existing {
+            if let Some(offer) = participant.offer {
+                let offer_data: Value = json!({
+                    "data_type": "offer",
+                    "from": participant.peer_id,
+                    "offer": offer,
+                });
+                let offer_data_string: String = serde_json::to_string(&offer_data).expect("Failed to serialize!");
+                deliver(storage.clone(), peers.clone(), bus.clone(), std::slice::from_ref(&peer_id), Message::Text(offer_data_string)).await;
+                debug!(from = %participant.peer_id, offer = ?offer, "stored offer replayed to joiner");
+            }
+
+            for candidate in participant.candidates {
                 let candidate_data: Value = json!({
                     "data_type": "candidate",
-                    "candidate": data.candidate
+                    "from": participant.peer_id,
+                    "candidate": candidate,
                 });
                 let candidate_data_string: String = serde_json::to_string(&candidate_data).expect("Failed to serialize!");
-                send_to_all(peers.clone(), Message::Text(candidate_data_string.clone())).await;
+                deliver(storage.clone(), peers.clone(), bus.clone(), std::slice::from_ref(&peer_id), Message::Text(candidate_data_string)).await;
+                debug!(from = %participant.peer_id, candidate = ?candidate, "stored candidate replayed to joiner");
+            }
+        }
 
-                println!("= send_candidate = candidate_data: {}", candidate_data_string.clone());
-            },
-            None => eprintln!("= send_candidate = The room do not exist!"),
+        let joined_data: Value = json!({
+            "data_type": "peer_joined",
+            "room_id": room_id,
+            "peer_id": peer_id,
+            "role": role,
+        });
+        deliver(storage.clone(), peers.clone(), bus.clone(), &others, Message::Text(joined_data.to_string())).await;
+        Ok(())
+    }
+
+    async fn create_invite(storage: SharedStorage, room_id: RoomId, role: Option<Role>, peers: PeerMap, bus: SharedBus, peer_id: PeerId) -> Result<(), Error> {
+        let (role, invite_token): (Role, String) = update_room(&storage, &room_id, |room| {
+            let role: Role = role.unwrap_or(room.access.default_role);
+            let invite_token: String = room.create_invite(&peer_id, role)?;
+            Ok((role, invite_token))
+        }).await?;
+
+        let invite_data: Value = json!({
+            "data_type": "invite",
+            "room_id": room_id,
+            "role": role,
+            "invite_token": invite_token,
+        });
+        deliver(storage.clone(), peers.clone(), bus.clone(), std::slice::from_ref(&peer_id), Message::Text(invite_data.to_string())).await;
+        Ok(())
+    }
+
+    async fn kick(storage: SharedStorage, room_id: RoomId, target: PeerId, peers: PeerMap, bus: SharedBus, peer_id: PeerId) -> Result<(), Error> {
+        let members: Vec<PeerId> = update_room(&storage, &room_id, |room| {
+            room.kick(&peer_id, &target)?;
+            Ok(room.members())
+        }).await?;
+
+        let kicked_data: Value = json!({
+            "data_type": "kicked",
+            "room_id": room_id,
+            "by": peer_id,
+        });
+        deliver(storage.clone(), peers.clone(), bus.clone(), std::slice::from_ref(&target), Message::Text(kicked_data.to_string())).await;
+
+        let left_data: Value = json!({
+            "data_type": "peer_left",
+            "room_id": room_id,
+            "peer_id": target,
+        });
+        deliver(storage.clone(), peers.clone(), bus.clone(), &members, Message::Text(left_data.to_string())).await;
+        Ok(())
+    }
+
+    async fn set_role(storage: SharedStorage, room_id: RoomId, target: PeerId, role: Role, peers: PeerMap, bus: SharedBus, peer_id: PeerId) -> Result<(), Error> {
+        let members: Vec<PeerId> = update_room(&storage, &room_id, |room| {
+            room.set_role(&peer_id, &target, role)?;
+            Ok(room.members())
+        }).await?;
+
+        let role_data: Value = json!({
+            "data_type": "role_changed",
+            "room_id": room_id,
+            "peer_id": target,
+            "role": role,
+        });
+        deliver(storage.clone(), peers.clone(), bus.clone(), &members, Message::Text(role_data.to_string())).await;
+        Ok(())
+    }
+
+    async fn close(storage: SharedStorage, peers: PeerMap, bus: SharedBus, peer_id: PeerId) {
+        // Notifying the rooms a peer leaves can surface further dead peers; clean those up too.
+        let mut departing: Vec<PeerId> = vec![peer_id];
+        while let Some(peer_id) = departing.pop() {
+            timed_lock(Lock::PeerMap, peers.write()).await.remove(&peer_id);
+
+            // A peer that is no longer registered was closed already, or detached from
+            // its rooms on purpose, as the shutdown drain does.
+            match storage.remove_peer(&peer_id).await {
+                Ok(true) => info!(peer_id = %peer_id, "connection closed"),
+                Ok(false) => {
+                    debug!(peer_id = %peer_id, "connection closed, keeping its room memberships");
+                    continue;
+                },
+                Err(e) => warn!(peer_id = %peer_id, error = %e, "failed to unregister the peer"),
+            }
+
+            match leave_rooms(&storage, &peer_id).await {
+                Ok(notices) => for (targets, notice) in notices {
+                    departing.extend(send_anywhere(&storage, &peers, &bus, &targets, Message::Text(notice.to_string())).await);
+                },
+                Err(e) => error!(peer_id = %peer_id, error = %e, "failed to remove the peer from its rooms"),
+            }
         }
     }
+}
 
-    async fn join_call(room: Option<ChatRoom>, peers: PeerMap) {
-        match room {
-            Some(exist_room) => {
-                let offer_data: Value = json!({
-                    "data_type": "offer",
-                    "offer": exist_room.lock().await.offer,
-                });
-                let offer_data_string: String = serde_json::to_string(&offer_data).expect("Failed to serialize!");
-                send_to_all(peers.clone(), Message::Text(offer_data_string.clone())).await;
-                println!("= join_call = offer_data: {}", offer_data_string.clone());
-                
-                for candidate in exist_room.lock().await.candidates.clone() {
-                    let candidate_data: Value = json!({
-                        "data_type": "candidate",
-                        "candidate": candidate,
-                    });
-                    let candidate_data_string: String = serde_json::to_string(&candidate_data).expect("Failed to serialize!");
-                    send_to_all(peers.clone(), Message::Text(candidate_data_string.clone())).await;
-                    println!("= join_call = candidate_data: {}", candidate_data_string.clone());
-                }
-            },
-            None => eprintln!("= join_call = The room do not exist!"),
+/// Removes `peer_id` from every room it is in, dropping rooms that close as a result,
+/// and returns the notices owed to the remaining members.
+async fn leave_rooms(storage: &SharedStorage, peer_id: &PeerId) -> Result<Vec<(Vec<PeerId>, Value)>, Error> {
+    let mut notices: Vec<(Vec<PeerId>, Value)> = Vec::new();
+    for room_id in storage.rooms_of(peer_id).await? {
+        notices.extend(leave_room(storage, &room_id, peer_id).await?);
+    }
+    Ok(notices)
+}
+
+async fn leave_room(storage: &SharedStorage, room_id: &str, peer_id: &PeerId) -> Result<Vec<(Vec<PeerId>, Value)>, Error> {
+    // Retried from a fresh read whenever another writer changed the room in between.
+    let (room, departure): (Room, Departure) = loop {
+        let Some(Versioned { value: mut room, version }) = storage.room(room_id).await? else {
+            return Ok(Vec::new());
+        };
+        let departure: Departure = room.depart(peer_id);
+        let written: bool = match departure {
+            Departure::NotMember => return Ok(Vec::new()),
+            Departure::Closed => storage.remove_room(room_id, version).await?,
+            _ => storage.replace_room(&room, version).await?,
+        };
+        if written {
+            break (room, departure);
         }
+    };
+
+    if departure == Departure::Closed {
+        return Ok(vec![(room.members(), json!({
+            "data_type": "room_closed",
+            "room_id": room_id,
+        }))]);
     }
+    let mut notices: Vec<(Vec<PeerId>, Value)> = vec![(room.members(), json!({
+        "data_type": "peer_left",
+        "room_id": room_id,
+        "peer_id": peer_id,
+    }))];
+    if let Departure::OwnerChanged(owner) = departure {
+        notices.push((room.members(), json!({
+            "data_type": "owner_changed",
+            "room_id": room_id,
+            "owner": owner,
+        })));
+    }
+    Ok(notices)
+}
 
-    async fn close(rooms: ChatRooms, peers: PeerMap, addr: SocketAddr) {
-        println!("[{}]: WebSocket connection closed!", addr);
-        rooms.lock().await.remove(&addr);
-        peers.lock().await.remove(&addr);
+pub async fn find_room(storage: &SharedStorage, room_id: &str) -> Result<Room, Error> {
+    let room: Option<Versioned<Room>> = storage.room(room_id).await?;
+    room.map(|room| room.value).ok_or(Error::RoomNotFound)
+}
+
+/// Checks `password` against the room's on the blocking pool, ahead of `update_room`,
+/// which could otherwise hash it again on every retry.
+async fn unlocks(storage: &SharedStorage, room_id: &str, password: String) -> Result<bool, Error> {
+    let room: Room = find_room(storage, room_id).await?;
+    Ok(spawn_blocking(move || room.access.unlocks(&password)).await.unwrap_or(false))
+}
+
+/// Makes a newly connected peer known to the storage, as connected to this node, and
+/// reachable through `peers`.
+pub async fn register(storage: &SharedStorage, peers: &PeerMap, bus: &SharedBus, peer_id: PeerId, peer: Peer) -> Result<(), Error> {
+    let record: PeerRecord = PeerRecord {
+        peer_id: peer_id.clone(),
+        addr: peer.addr,
+        user_id: peer.identity.as_ref().map(|identity| identity.user_id.clone()),
+        node_id: bus.node_id().to_string(),
+    };
+    storage.insert_peer(&record).await?;
+    timed_lock(Lock::PeerMap, peers.write()).await.insert(peer_id, peer);
+    Ok(())
+}
+
+/// Queues `msg` on each target's outbound channel without waiting on any socket.
+/// Peers whose queue is closed or full are dropped from `peers` and returned so the
+/// caller can clean up their rooms; delivery to the other targets carries on.
+pub async fn send_to(peers: PeerMap, targets: &[PeerId], msg: Message) -> Vec<PeerId> {
+    let started: Instant = Instant::now();
+    let mut dead_peers: Vec<PeerId> = Vec::new();
+    let mut queued: u64 = 0;
+    for peer_id in targets {
+        if let Some(peer) = timed_lock(Lock::PeerMap, peers.read()).await.get(peer_id) {
+            trace!(peer_id = %peer_id, addr = %peer.addr, "queueing message");
+            match peer.tx.try_send(msg.clone()) {
+                Ok(()) => queued += 1,
+                Err(TrySendError::Full(_)) => {
+                    warn!(peer_id = %peer_id, "outbound queue is full, dropping the peer");
+                    METRICS.send_failures.inc("queue_full");
+                    dead_peers.push(peer_id.clone());
+                },
+                Err(TrySendError::Closed(_)) => {
+                    warn!(peer_id = %peer_id, "outbound queue is closed, dropping the peer");
+                    METRICS.send_failures.inc("queue_closed");
+                    dead_peers.push(peer_id.clone());
+                },
+            }
+        }
+    }
+    if queued > 0 {
+        METRICS.messages_out.add(&data_type_of(&msg), queued);
+    }
+    METRICS.fanout.observe(started.elapsed());
 
-        println!("= WebSocket Closed = peers: {:?}", peers);
-        println!("= WebSocket Closed = rooms: {:?}", rooms);
+    if !dead_peers.is_empty() {
+        let mut peers = timed_lock(Lock::PeerMap, peers.write()).await;
+        for peer_id in dead_peers.iter() {
+            peers.remove(peer_id);
+        }
     }
+    dead_peers
 }
 
-pub async fn find_room(rooms: &ChatRooms, room_id: String) -> Option<ChatRoom> {
-    let rooms: MutexGuard<'_, HashMap<SocketAddr, Arc<Mutex<Room>>>> = rooms.lock().await;
-    for (_, room) in rooms.iter() {
-        if room.lock().await.room_id == room_id {
-            return Some(room.clone())
+/// [`send_to`] for the targets connected here; the others are relayed over the bus to
+/// the nodes they are connected to. Returns the local peers that could not be reached.
+async fn send_anywhere(storage: &SharedStorage, peers: &PeerMap, bus: &SharedBus, targets: &[PeerId], msg: Message) -> Vec<PeerId> {
+    let remote: Vec<PeerId> = {
+        let peers = timed_lock(Lock::PeerMap, peers.read()).await;
+        targets.iter().filter(|peer_id| !peers.contains_key(*peer_id)).cloned().collect()
+    };
+    if !remote.is_empty() {
+        for node_id in bus::relay(storage, bus, &remote, &msg).await {
+            spawn(bus::reap(storage.clone(), peers.clone(), bus.clone(), node_id, bus::REAP_GRACE));
         }
     }
-    None
+    send_to(peers.clone(), targets, msg).await
 }
 
-async fn send_to_all(peers: PeerMap, msg: Message) {
-    for (addr, write) in peers.lock().await.iter_mut() {
-        println!("send to [{}]", addr);
-        write.send(msg.clone()).await.expect("Failed to send msg to all!")
+/// [`send_anywhere`], then takes any peer that could not be reached through the same
+/// cleanup as a closed connection.
+pub async fn deliver(storage: SharedStorage, peers: PeerMap, bus: SharedBus, targets: &[PeerId], msg: Message) {
+    for dead_peer in send_anywhere(&storage, &peers, &bus, targets, msg).await {
+        DataType::close(storage.clone(), peers.clone(), bus.clone(), dead_peer).await;
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Offer {
     pub r#type: String,
     pub sdp: String,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Answer {
     r#type: String,
     sdp: String,
 }
 
 #[allow(non_snake_case)]
-#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[derive(PartialEq, Serialize, Deserialize, Clone)]
 pub struct Candidate {
     candidate: String,
     sdpMid: String,
@@ -172,333 +414,947 @@ pub struct Candidate {
     usernameFragment: String,
 }
 
-#[derive(Debug, Clone)]
-pub struct Room {
-    pub room_id: String,
-    pub offer: Offer,
+// SDP and candidates carry addresses and ICE credentials, so their Debug output goes
+// through the log redaction.
+impl fmt::Debug for Offer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Offer").field("type", &self.r#type).field("sdp", &redact(&self.sdp)).finish()
+    }
+}
+
+impl fmt::Debug for Answer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Answer").field("type", &self.r#type).field("sdp", &redact(&self.sdp)).finish()
+    }
+}
+
+impl fmt::Debug for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Candidate")
+        .field("candidate", &redact(&self.candidate))
+        .field("sdpMid", &self.sdpMid)
+        .field("sdpMLineIndex", &self.sdpMLineIndex)
+        .field("usernameFragment", &redact(&self.usernameFragment))
+        .finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Participant {
+    pub peer_id: PeerId,
+    pub role: Role,
+    pub offer: Option<Offer>,
     pub candidates: Vec<Candidate>,
 }
 
+impl Participant {
+    pub fn new(peer_id: PeerId, role: Role) -> Self {
+        Participant {
+            peer_id,
+            role,
+            offer: None,
+            candidates: Vec::new(),
+        }
+    }
+}
+
+/// What a room member may do. The room owner is always a host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Publishes media and moderates the room: invites, kicks and changes roles.
+    Host,
+    /// Publishes media, i.e. may store and send offers.
+    #[default]
+    Presenter,
+    /// Only receives media; may still answer offers and exchange candidates.
+    Viewer,
+}
+
+impl Role {
+    pub fn can_publish(&self) -> bool {
+        *self != Role::Viewer
+    }
+
+    pub fn can_moderate(&self) -> bool {
+        *self == Role::Host
+    }
+}
+
+/// Who may join a room created by `store_room`. Open by default.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RoomAccess {
+    password: Option<PasswordHash>,
+    /// Only peers holding an invite from a host may join.
+    pub invite_only: bool,
+    /// Role of peers joining without an invite.
+    pub default_role: Role,
+    /// Unused invite tokens and the role each grants.
+    invites: HashMap<String, Role>,
+}
+
+impl RoomAccess {
+    pub fn new(password: Option<&str>, invite_only: bool, default_role: Role) -> Self {
+        RoomAccess {
+            password: password.map(PasswordHash::new),
+            invite_only,
+            default_role,
+            invites: HashMap::new(),
+        }
+    }
+
+    /// Whether `password` is the room password. Slow on purpose, so async callers run it
+    /// on the blocking pool. False for rooms without a password.
+    pub fn unlocks(&self, password: &str) -> bool {
+        self.password.as_ref().is_some_and(|hash| hash.matches(password))
+    }
+}
+
+/// PBKDF2-HMAC-SHA256 rounds for new room passwords, as recommended by OWASP. Tests
+/// use far fewer to stay fast in debug builds.
+const PASSWORD_ROUNDS: u32 = if cfg!(test) { 1_000 } else { 600_000 };
+
+/// Salted PBKDF2 of a room password, so the plain text never sits in the room map and a
+/// leaked row is expensive to brute-force.
+#[derive(Clone, Serialize, Deserialize)]
+struct PasswordHash {
+    salt: String,
+    rounds: u32,
+    digest: Vec<u8>,
+}
+
+impl PasswordHash {
+    fn new(password: &str) -> Self {
+        let salt: String = Uuid::new_v4().to_string();
+        let digest: Vec<u8> = PasswordHash::digest(&salt, PASSWORD_ROUNDS, password);
+        PasswordHash { salt, rounds: PASSWORD_ROUNDS, digest }
+    }
+
+    fn digest(salt: &str, rounds: u32, password: &str) -> Vec<u8> {
+        let mut digest: [u8; 32] = [0; 32];
+        pbkdf2_hmac::<Sha256>(password.as_bytes(), salt.as_bytes(), rounds, &mut digest);
+        digest.to_vec()
+    }
+
+    fn matches(&self, password: &str) -> bool {
+        let digest: Vec<u8> = PasswordHash::digest(&self.salt, self.rounds, password);
+        digest.ct_eq(&self.digest).into()
+    }
+}
+
+impl fmt::Debug for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PasswordHash(..)")
+    }
+}
+
+/// What happens to a room when its owner leaves while other members remain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomPolicy {
+    /// Close the room and notify the remaining members.
+    #[default]
+    Destroy,
+    /// Hand ownership to the longest-standing remaining member.
+    TransferOwnership,
+    /// Leave the room ownerless until the last member leaves.
+    KeepUntilEmpty,
+}
+
+impl FromStr for RoomPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "destroy" => Ok(RoomPolicy::Destroy),
+            "transfer_ownership" => Ok(RoomPolicy::TransferOwnership),
+            "keep_until_empty" => Ok(RoomPolicy::KeepUntilEmpty),
+            _ => Err(String::from("expected one of destroy, transfer_ownership, keep_until_empty")),
+        }
+    }
+}
+
+/// Outcome of a peer leaving a room, see [`Room::depart`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Departure {
+    NotMember,
+    Left,
+    OwnerChanged(PeerId),
+    Closed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Room {
+    pub room_id: RoomId,
+    pub owner: Option<PeerId>,
+    pub policy: RoomPolicy,
+    pub access: RoomAccess,
+    pub participants: Vec<Participant>,
+}
+
+impl Room {
+    pub fn new(room_id: RoomId, creator: PeerId, policy: RoomPolicy) -> Self {
+        Room {
+            room_id,
+            owner: Some(creator.clone()),
+            policy,
+            access: RoomAccess::default(),
+            participants: vec![Participant::new(creator, Role::Host)],
+        }
+    }
+
+    pub fn is_owner(&self, peer_id: &PeerId) -> bool {
+        self.owner.as_ref() == Some(peer_id)
+    }
+
+    /// Removes `peer_id` from the room and applies the room policy if it was the owner.
+    /// `Departure::Closed` means the room should be dropped.
+    pub fn depart(&mut self, peer_id: &PeerId) -> Departure {
+        if !self.remove_participant(peer_id) {
+            return Departure::NotMember
+        }
+        if self.participants.is_empty() {
+            return Departure::Closed
+        }
+        if !self.is_owner(peer_id) {
+            return Departure::Left
+        }
+        match self.policy {
+            RoomPolicy::Destroy => Departure::Closed,
+            RoomPolicy::TransferOwnership => {
+                self.participants[0].role = Role::Host;
+                let owner: PeerId = self.participants[0].peer_id.clone();
+                self.owner = Some(owner.clone());
+                Departure::OwnerChanged(owner)
+            },
+            RoomPolicy::KeepUntilEmpty => {
+                self.owner = None;
+                Departure::Left
+            },
+        }
+    }
+
+    pub fn members(&self) -> Vec<PeerId> {
+        self.participants.iter().map(|participant| participant.peer_id.clone()).collect()
+    }
+
+    pub fn is_member(&self, peer_id: &PeerId) -> bool {
+        self.participants.iter().any(|participant| &participant.peer_id == peer_id)
+    }
+
+    pub fn participant_mut(&mut self, peer_id: &PeerId) -> Option<&mut Participant> {
+        self.participants.iter_mut().find(|participant| &participant.peer_id == peer_id)
+    }
+
+    pub fn add_participant(&mut self, peer_id: PeerId) {
+        if !self.is_member(&peer_id) {
+            self.participants.push(Participant::new(peer_id, self.access.default_role));
+        }
+    }
+
+    pub fn role_of(&self, peer_id: &PeerId) -> Option<Role> {
+        self.participants.iter().find(|participant| &participant.peer_id == peer_id).map(|participant| participant.role)
+    }
+
+    /// Fails unless `peer_id` is a member whose role passes `allowed`.
+    pub fn require(&self, peer_id: &PeerId, allowed: fn(&Role) -> bool, action: &str) -> Result<(), Error> {
+        match self.role_of(peer_id) {
+            Some(role) if allowed(&role) => Ok(()),
+            Some(_) => Err(Error::Forbidden(action.to_string())),
+            None => Err(Error::NotInRoom(peer_id.clone())),
+        }
+    }
+
+    /// Adds `peer_id` if it holds a valid invite, or gave the room password when one is
+    /// set and the room is not invite-only. `unlocked` is the outcome of checking that
+    /// password with [`RoomAccess::unlocks`]. Invites are consumed on use. Members
+    /// rejoining keep their role.
+    pub fn admit(&mut self, peer_id: &PeerId, unlocked: bool, invite_token: Option<&str>) -> Result<Role, Error> {
+        if let Some(role) = self.role_of(peer_id) {
+            return Ok(role)
+        }
+        let role: Role = match invite_token.and_then(|invite_token| self.access.invites.remove(invite_token)) {
+            Some(role) => role,
+            None if self.access.invite_only => return Err(Error::AccessDenied),
+            None if self.access.password.is_some() && !unlocked => return Err(Error::AccessDenied),
+            None => self.access.default_role,
+        };
+        self.participants.push(Participant::new(peer_id.clone(), role));
+        Ok(role)
+    }
+
+    /// Issues a single-use invite token granting `role`. Hosts only.
+    pub fn create_invite(&mut self, peer_id: &PeerId, role: Role) -> Result<String, Error> {
+        self.require(peer_id, Role::can_moderate, "create invites")?;
+        let invite_token: String = Uuid::new_v4().simple().to_string();
+        self.access.invites.insert(invite_token.clone(), role);
+        Ok(invite_token)
+    }
+
+    /// Removes `target` from the room on behalf of the host `peer_id`. Neither the owner
+    /// nor the host itself can be kicked.
+    pub fn kick(&mut self, peer_id: &PeerId, target: &PeerId) -> Result<(), Error> {
+        self.require(peer_id, Role::can_moderate, "kick members")?;
+        if target == peer_id {
+            return Err(Error::Forbidden(String::from("kick yourself")))
+        }
+        if self.is_owner(target) {
+            return Err(Error::Forbidden(String::from("kick the room owner")))
+        }
+        if !self.remove_participant(target) {
+            return Err(Error::PeerUnreachable(Some(target.clone())))
+        }
+        Ok(())
+    }
+
+    /// Changes the role of `target` on behalf of the host `peer_id`. The owner always
+    /// stays a host.
+    pub fn set_role(&mut self, peer_id: &PeerId, target: &PeerId, role: Role) -> Result<(), Error> {
+        self.require(peer_id, Role::can_moderate, "change roles")?;
+        if self.is_owner(target) {
+            return Err(Error::Forbidden(String::from("change the owner's role")))
+        }
+        match self.participant_mut(target) {
+            Some(participant) => {
+                participant.role = role;
+                Ok(())
+            },
+            None => Err(Error::PeerUnreachable(Some(target.clone()))),
+        }
+    }
+
+    /// Returns whether `peer_id` was a participant before the call.
+    pub fn remove_participant(&mut self, peer_id: &PeerId) -> bool {
+        let before: usize = self.participants.len();
+        self.participants.retain(|participant| &participant.peer_id != peer_id);
+        self.participants.len() != before
+    }
+
+    pub fn other_members(&self, peer_id: &PeerId) -> Vec<PeerId> {
+        self.members().into_iter().filter(|member| member != peer_id).collect()
+    }
+
+    /// Resolves who a message from `peer_id` should reach: the single `to` peer when
+    /// one is named, otherwise every other member.
+    pub fn recipients(&self, peer_id: &PeerId, to: &Option<PeerId>) -> Result<Vec<PeerId>, Error> {
+        if !self.is_member(peer_id) {
+            return Err(Error::NotInRoom(peer_id.clone()))
+        }
+        match to {
+            Some(target) if target != peer_id && self.is_member(target) => Ok(vec![target.clone()]),
+            Some(_) => Err(Error::PeerUnreachable(to.clone())),
+            None => Ok(self.other_members(peer_id)),
+        }
+    }
+}
+
+/// Wraps a [`SignalMessage`] with the optional client-supplied `request_id` that
+/// replies to it are correlated by.
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct StoreRoom {
-    pub data_type: String,
-    pub room_id: String,
-    pub offer: Offer,
-    pub answer: Answer,
-    pub candidate: Candidate,
+pub struct SignalEnvelope {
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub message: SignalMessage,
+}
+
+/// Inbound signaling message, tagged by `data_type`. Each variant only carries the
+/// fields its command needs; `to` addresses a single room member instead of all of them.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "data_type", rename_all = "snake_case")]
+pub enum SignalMessage {
+    StoreRoom {
+        room_id: String,
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        invite_only: bool,
+        #[serde(default)]
+        default_role: Role,
+    },
+    StoreOffer {
+        room_id: String,
+        offer: Offer,
+    },
+    StoreCandidate {
+        room_id: String,
+        candidate: Candidate,
+    },
+    SendOffer {
+        room_id: String,
+        offer: Offer,
+        #[serde(default)]
+        to: Option<PeerId>,
+    },
+    SendAnswer {
+        room_id: String,
+        answer: Answer,
+        #[serde(default)]
+        to: Option<PeerId>,
+    },
+    SendCandidate {
+        room_id: String,
+        candidate: Candidate,
+        #[serde(default)]
+        to: Option<PeerId>,
+    },
+    JoinCall {
+        room_id: String,
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        invite_token: Option<String>,
+    },
+    CreateInvite {
+        room_id: String,
+        #[serde(default)]
+        role: Option<Role>,
+    },
+    Kick {
+        room_id: String,
+        peer_id: PeerId,
+    },
+    SetRole {
+        room_id: String,
+        peer_id: PeerId,
+        role: Role,
+    },
+}
+
+impl SignalMessage {
+    pub fn room_id(&self) -> &str {
+        match self {
+            SignalMessage::StoreRoom { room_id, .. }
+            | SignalMessage::StoreOffer { room_id, .. }
+            | SignalMessage::StoreCandidate { room_id, .. }
+            | SignalMessage::SendOffer { room_id, .. }
+            | SignalMessage::SendAnswer { room_id, .. }
+            | SignalMessage::SendCandidate { room_id, .. }
+            | SignalMessage::JoinCall { room_id, .. }
+            | SignalMessage::CreateInvite { room_id, .. }
+            | SignalMessage::Kick { room_id, .. }
+            | SignalMessage::SetRole { room_id, .. } => room_id,
+        }
+    }
+
+    pub fn data_type(&self) -> &'static str {
+        match self {
+            SignalMessage::StoreRoom { .. } => "store_room",
+            SignalMessage::StoreOffer { .. } => "store_offer",
+            SignalMessage::StoreCandidate { .. } => "store_candidate",
+            SignalMessage::SendOffer { .. } => "send_offer",
+            SignalMessage::SendAnswer { .. } => "send_answer",
+            SignalMessage::SendCandidate { .. } => "send_candidate",
+            SignalMessage::JoinCall { .. } => "join_call",
+            SignalMessage::CreateInvite { .. } => "create_invite",
+            SignalMessage::Kick { .. } => "kick",
+            SignalMessage::SetRole { .. } => "set_role",
+        }
+    }
+
+    /// The `ack` message confirming that the command tagged with `request_id` succeeded.
+    pub fn ack(&self, request_id: String) -> Value {
+        json!({
+            "data_type": "ack",
+            "command": self.data_type(),
+            "room_id": self.room_id(),
+            "request_id": request_id,
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use futures_util::StreamExt;
-    use hyper::{Request, Body};
-    use hyper::upgrade::on;
-    use std::net::{IpAddr, Ipv4Addr};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
     use std::sync::Arc;
     use std::collections::HashMap;
-    use tokio_tungstenite::WebSocketStream;
+    use std::time::Duration;
+    use tokio::sync::{mpsc::{channel, Receiver}, RwLock};
+    use tokio::{spawn, time::timeout};
+
+    use crate::websocket::bus::local::LocalHub;
+    use crate::websocket::store::memory::MemoryStorage;
+    use crate::websocket::OUTBOUND_QUEUE_SIZE;
+
+    async fn test_peer(storage: &SharedStorage, peers: &PeerMap, bus: &SharedBus, peer_id: &PeerId) -> Receiver<Message> {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let (tx, rx) = channel(OUTBOUND_QUEUE_SIZE);
+        register(storage, peers, bus, peer_id.clone(), Peer { addr, tx, identity: None }).await.unwrap();
+        rx
+    }
+
+    async fn stored_room(storage: &SharedStorage, room_id: &str) -> Room {
+        find_room(storage, room_id).await.expect("room is not stored")
+    }
+
+    fn next_msg(rx: &mut Receiver<Message>) -> Value {
+        serde_json::from_str(&rx.try_recv().expect("no queued message").to_string()).unwrap()
+    }
+
+    fn test_offer() -> Offer {
+        Offer {
+            r#type: String::from("offer"),
+            sdp: String::from("sdp_offer"),
+        }
+    }
+
+    fn test_answer() -> Answer {
+        Answer {
+            r#type: String::from("answer"),
+            sdp: String::from("sdp_answer"),
+        }
+    }
+
+    fn test_candidate() -> Candidate {
+        Candidate {
+            candidate: String::from("candidate"),
+            sdpMid: String::from("sdpMid"),
+            sdpMLineIndex: 0,
+            usernameFragment: String::from("usernameFragment"),
+        }
+    }
 
     #[tokio::test]
     async fn test_store_room() {
-        let rooms: ChatRooms = Arc::new(Mutex::new(HashMap::new()));
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-        let data = StoreRoom {
-            data_type: String::from("test"),
-            room_id: String::from("test_room"),
-            offer: Offer {
-                r#type: String::from("offer"),
-                sdp: String::from("sdp_offer"),
-            },
-            answer: Answer {
-                r#type: String::from("answer"),
-                sdp: String::from("sdp_answer"),
-            },
-            candidate: Candidate {
-                candidate: String::from("candidate"),
-                sdpMid: String::from("sdpMid"),
-                sdpMLineIndex: 0,
-                usernameFragment: String::from("usernameFragment"),
-            },
-        };
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let peer_id: PeerId = String::from("test_peer");
+        let room_id: String = String::from("test_room");
 
-        DataType::store_room(rooms.clone(), None, data.clone(), addr).await;
+        assert!(DataType::store_room(storage.clone(), room_id.clone(), peer_id.clone(), RoomPolicy::default(), RoomAccess::default()).await.is_ok());
+        assert_eq!(DataType::store_room(storage.clone(), room_id.clone(), peer_id.clone(), RoomPolicy::default(), RoomAccess::default()).await, Err(Error::RoomExists(room_id.clone())));
 
-        let binding = rooms.lock().await;
-        let room = binding.get(&addr);
-        assert!(room.is_some());
-        assert_eq!(room.unwrap().lock().await.room_id, data.clone().room_id);
+        let room: Room = stored_room(&storage, "test_room").await;
+        assert_eq!(room.room_id, room_id);
+        assert_eq!(room.members(), vec![peer_id.clone()]);
     }
 
     #[tokio::test]
     async fn test_store_offer() {
-        let rooms: ChatRooms = Arc::new(Mutex::new(HashMap::new()));
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-        let data = StoreRoom {
-            data_type: String::from("test"),
-            room_id: String::from("test_room"),
-            offer: Offer {
-                r#type: String::from("offer"),
-                sdp: String::from("sdp_offer"),
-            },
-            answer: Answer {
-                r#type: String::from("answer"),
-                sdp: String::from("sdp_answer"),
-            },
-            candidate: Candidate {
-                candidate: String::from("candidate"),
-                sdpMid: String::from("sdpMid"),
-                sdpMLineIndex: 0,
-                usernameFragment: String::from("usernameFragment"),
-            },
-        };
-
-        let new_room: Room = Room { 
-            room_id: data.room_id.clone(),
-            offer: Offer { 
-                r#type: String::new(), 
-                sdp: String::new(), 
-            },
-            candidates: Vec::new(),
-        };
-        rooms.lock().await.insert(addr, Arc::new(Mutex::new(new_room.clone())));
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let peer_id: PeerId = String::from("test_peer");
+        let new_room: Room = Room::new(String::from("test_room"), peer_id.clone(), RoomPolicy::default());
+        assert!(storage.insert_room(&new_room).await.unwrap());
 
-        DataType::store_offer(rooms.clone(), rooms.lock().await.get(&addr).cloned(), data.clone()).await;
+        assert!(DataType::store_offer(storage.clone(), String::from("test_room"), test_offer(), peer_id.clone()).await.is_ok());
+        assert_eq!(DataType::store_offer(storage.clone(), String::from("missing_room"), test_offer(), peer_id.clone()).await, Err(Error::RoomNotFound));
 
-        let binding = rooms.lock().await;
-        let room = binding.get(&addr);
-        assert!(room.is_some());
-        assert_eq!(room.unwrap().lock().await.offer, data.offer);
+        assert_eq!(stored_room(&storage, "test_room").await.participants[0].offer, Some(test_offer()));
     }
 
     #[tokio::test]
     async fn test_store_candidate() {
-        let rooms: ChatRooms = Arc::new(Mutex::new(HashMap::new()));
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-        let data = StoreRoom {
-            data_type: String::from("test"),
-            room_id: String::from("test_room"),
-            offer: Offer {
-                r#type: String::from("offer"),
-                sdp: String::from("sdp_offer"),
-            },
-            answer: Answer {
-                r#type: String::from("answer"),
-                sdp: String::from("sdp_answer"),
-            },
-            candidate: Candidate {
-                candidate: String::from("candidate"),
-                sdpMid: String::from("sdpMid"),
-                sdpMLineIndex: 0,
-                usernameFragment: String::from("usernameFragment"),
-            },
-        };
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let peer_id: PeerId = String::from("test_peer");
+        let new_room: Room = Room::new(String::from("test_room"), peer_id.clone(), RoomPolicy::default());
+        assert!(storage.insert_room(&new_room).await.unwrap());
 
-        let new_room: Room = Room { 
-            room_id: data.room_id.clone(),
-            offer: Offer { 
-                r#type: String::new(), 
-                sdp: String::new(), 
-            },
-            candidates: Vec::new(),
-        };
-        rooms.lock().await.insert(addr, Arc::new(Mutex::new(new_room.clone())));
+        assert!(DataType::store_candidate(storage.clone(), String::from("test_room"), test_candidate(), peer_id.clone()).await.is_ok());
+        let result = DataType::store_candidate(storage.clone(), String::from("test_room"), test_candidate(), String::from("stranger")).await;
+        assert_eq!(result, Err(Error::NotInRoom(String::from("stranger"))));
 
-        DataType::store_candidate(rooms.clone(), rooms.lock().await.get(&addr).cloned(), data.clone()).await;
-
-        let binding = rooms.lock().await;
-        let room = binding.get(&addr);
-        assert!(room.is_some());
-        assert_eq!(room.unwrap().lock().await.candidates[0], data.candidate);
+        let room: Room = stored_room(&storage, "test_room").await;
+        assert_eq!(room.participants[0].candidates, vec![test_candidate()]);
+        assert_eq!(room.participants.len(), 1);
     }
 
     #[tokio::test]
     async fn test_send_answer() {
-        let rooms: ChatRooms = Arc::new(Mutex::new(HashMap::new()));
-        let peers: PeerMap = Arc::new(Mutex::new(HashMap::new()));
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-        let mut req = Request::new(Body::empty());
-        if let Ok(upgraded) = on(&mut req).await {
-            let (write, _) = WebSocketStream::from_raw_socket(upgraded, tokio_tungstenite::tungstenite::protocol::Role::Client, None).await.split();
-            peers.lock().await.insert(addr, write);
-        }
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let peers: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let bus: SharedBus = Arc::new(LocalHub::new().join(String::from("node_a")));
+        let peer_id: PeerId = String::from("test_peer");
+        let receiver_id: PeerId = String::from("receiver");
+        let mut peer_rx: Receiver<Message> = test_peer(&storage, &peers, &bus, &peer_id).await;
+        let mut receiver_rx: Receiver<Message> = test_peer(&storage, &peers, &bus, &receiver_id).await;
 
-        let new_room: Room = Room { 
-            room_id: String::from("test_room"),
-            offer: Offer { 
-                r#type: String::new(), 
-                sdp: String::new(), 
-            },
-            candidates: Vec::new(),
-        };
-        rooms.lock().await.insert(addr, Arc::new(Mutex::new(new_room.clone())));
-
-        let data = StoreRoom {
-            data_type: String::from("test"),
-            room_id: String::from("test_room"),
-            offer: Offer {
-                r#type: String::from("offer"),
-                sdp: String::from("sdp_offer"),
-            },
-            answer: Answer {
-                r#type: String::from("answer"),
-                sdp: String::from("sdp_answer"),
-            },
-            candidate: Candidate {
-                candidate: String::from("candidate"),
-                sdpMid: String::from("sdpMid"),
-                sdpMLineIndex: 0,
-                usernameFragment: String::from("usernameFragment"),
-            },
-        };
+        let mut new_room: Room = Room::new(String::from("test_room"), peer_id.clone(), RoomPolicy::default());
+        new_room.add_participant(receiver_id.clone());
+        assert!(storage.insert_room(&new_room).await.unwrap());
 
-        DataType::send_answer(rooms.lock().await.get(&addr).cloned(), data.clone(), peers.clone()).await;
+        assert!(DataType::send_answer(storage.clone(), String::from("test_room"), test_answer(), None, peers.clone(), bus.clone(), peer_id.clone()).await.is_ok());
+        let result = DataType::send_answer(storage.clone(), String::from("test_room"), test_answer(), Some(String::from("stranger")), peers.clone(), bus.clone(), peer_id.clone()).await;
+        assert_eq!(result, Err(Error::PeerUnreachable(Some(String::from("stranger")))));
 
-        let binding = rooms.lock().await;
-        let room = binding.get(&addr);
-        assert!(room.is_some());
+        let answer_data: Value = next_msg(&mut receiver_rx);
+        assert_eq!(answer_data["data_type"], "answer");
+        assert_eq!(answer_data["from"], "test_peer");
+        assert_eq!(answer_data["answer"]["sdp"], "sdp_answer");
+        assert!(receiver_rx.try_recv().is_err());
+        assert!(peer_rx.try_recv().is_err());
     }
 
     #[tokio::test]
     async fn test_send_candidate() {
-        let rooms: ChatRooms = Arc::new(Mutex::new(HashMap::new()));
-        let peers: PeerMap = Arc::new(Mutex::new(HashMap::new()));
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-        let mut req = Request::new(Body::empty());
-        if let Ok(upgraded) = on(&mut req).await {
-            let (write, _) = WebSocketStream::from_raw_socket(upgraded, tokio_tungstenite::tungstenite::protocol::Role::Client, None).await.split();
-            peers.lock().await.insert(addr, write);
-        }
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let peers: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let bus: SharedBus = Arc::new(LocalHub::new().join(String::from("node_a")));
+        let peer_id: PeerId = String::from("test_peer");
+        let receiver_id: PeerId = String::from("receiver");
+        let bystander_id: PeerId = String::from("bystander");
+        let mut receiver_rx: Receiver<Message> = test_peer(&storage, &peers, &bus, &receiver_id).await;
+        let mut bystander_rx: Receiver<Message> = test_peer(&storage, &peers, &bus, &bystander_id).await;
 
-        let new_room: Room = Room { 
-            room_id: String::from("test_room"),
-            offer: Offer { 
-                r#type: String::new(), 
-                sdp: String::new(), 
-            },
-            candidates: Vec::new(),
-        };
-        rooms.lock().await.insert(addr, Arc::new(Mutex::new(new_room.clone())));
-
-        let data = StoreRoom {
-            data_type: String::from("test"),
-            room_id: String::from("test_room"),
-            offer: Offer {
-                r#type: String::from("offer"),
-                sdp: String::from("sdp_offer"),
-            },
-            answer: Answer {
-                r#type: String::from("answer"),
-                sdp: String::from("sdp_answer"),
-            },
-            candidate: Candidate {
-                candidate: String::from("candidate"),
-                sdpMid: String::from("sdpMid"),
-                sdpMLineIndex: 0,
-                usernameFragment: String::from("usernameFragment"),
-            },
-        };
+        let mut new_room: Room = Room::new(String::from("test_room"), peer_id.clone(), RoomPolicy::default());
+        new_room.add_participant(receiver_id.clone());
+        new_room.add_participant(bystander_id.clone());
+        assert!(storage.insert_room(&new_room).await.unwrap());
 
-        DataType::send_candidate(rooms.lock().await.get(&addr).cloned(), data.clone(), peers.clone()).await;
+        let result = DataType::send_candidate(storage.clone(), String::from("test_room"), test_candidate(), Some(receiver_id.clone()), peers.clone(), bus.clone(), peer_id.clone()).await;
+        assert!(result.is_ok());
+        assert_eq!(DataType::send_candidate(storage.clone(), String::from("missing_room"), test_candidate(), None, peers.clone(), bus.clone(), peer_id.clone()).await, Err(Error::RoomNotFound));
 
-        let binding = rooms.lock().await;
-        let room = binding.get(&addr);
-        assert!(room.is_some());
+        let candidate_data: Value = next_msg(&mut receiver_rx);
+        assert_eq!(candidate_data["data_type"], "candidate");
+        assert_eq!(candidate_data["candidate"]["candidate"], "candidate");
+        assert!(bystander_rx.try_recv().is_err());
     }
 
     #[tokio::test]
     async fn test_join_call() {
-        let rooms: ChatRooms = Arc::new(Mutex::new(HashMap::new()));
-        let peers: PeerMap = Arc::new(Mutex::new(HashMap::new()));
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-        let mut req = Request::new(Body::empty());
-        if let Ok(upgraded) = on(&mut req).await {
-            let (write, _) = WebSocketStream::from_raw_socket(upgraded, tokio_tungstenite::tungstenite::protocol::Role::Client, None).await.split();
-            peers.lock().await.insert(addr, write);
-        }
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let peers: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let bus: SharedBus = Arc::new(LocalHub::new().join(String::from("node_a")));
+        let peer_id: PeerId = String::from("test_peer");
+        let joiner_id: PeerId = String::from("joiner");
+        let mut peer_rx: Receiver<Message> = test_peer(&storage, &peers, &bus, &peer_id).await;
+        let mut joiner_rx: Receiver<Message> = test_peer(&storage, &peers, &bus, &joiner_id).await;
 
+        let mut new_room: Room = Room::new(String::from("test_room"), peer_id.clone(), RoomPolicy::default());
+        new_room.participants[0].offer = Some(test_offer());
+        new_room.participants[0].candidates.push(test_candidate());
+        assert!(storage.insert_room(&new_room).await.unwrap());
 
-        let new_room: Room = Room { 
-            room_id: String::from("test_room"),
-            offer: Offer { 
-                r#type: String::new(), 
-                sdp: String::new(), 
-            },
-            candidates: vec![
-                Candidate {
-                    candidate: String::from("candidate"),
-                    sdpMid: String::from("sdpMid"),
-                    sdpMLineIndex: 0,
-                    usernameFragment: String::from("usernameFragment"),
-                }
-            ],
-        };
+        assert!(DataType::join_call(storage.clone(), String::from("test_room"), peers.clone(), bus.clone(), joiner_id.clone(), None, None).await.is_ok());
+        assert!(DataType::join_call(storage.clone(), String::from("test_room"), peers.clone(), bus.clone(), joiner_id.clone(), None, None).await.is_ok());
+        assert_eq!(DataType::join_call(storage.clone(), String::from("missing_room"), peers.clone(), bus.clone(), joiner_id.clone(), None, None).await, Err(Error::RoomNotFound));
 
-        rooms.lock().await.insert(addr, Arc::new(Mutex::new(new_room.clone())));
+        assert_eq!(stored_room(&storage, "test_room").await.members(), vec![peer_id.clone(), joiner_id.clone()]);
 
-        DataType::join_call(rooms.lock().await.get(&addr).cloned(), peers.clone()).await;
+        let members_data: Value = next_msg(&mut joiner_rx);
+        assert_eq!(members_data["data_type"], "room_members");
+        assert_eq!(members_data["peers"], json!(["test_peer"]));
+        let offer_data: Value = next_msg(&mut joiner_rx);
+        assert_eq!(offer_data["data_type"], "offer");
+        assert_eq!(offer_data["from"], "test_peer");
+        let candidate_data: Value = next_msg(&mut joiner_rx);
+        assert_eq!(candidate_data["data_type"], "candidate");
 
-        let binding = rooms.lock().await;
-        let room = binding.get(&addr);
-        assert!(room.is_some());
+        let joined_data: Value = next_msg(&mut peer_rx);
+        assert_eq!(joined_data["data_type"], "peer_joined");
+        assert_eq!(joined_data["peer_id"], "joiner");
     }
 
     #[tokio::test]
     async fn test_close() {
-        let rooms: ChatRooms = Arc::new(Mutex::new(HashMap::new()));
-        let peers: PeerMap = Arc::new(Mutex::new(HashMap::new()));
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-        let mut req = Request::new(Body::empty());
-        if let Ok(upgraded) = on(&mut req).await {
-            let (write, _) = WebSocketStream::from_raw_socket(upgraded, tokio_tungstenite::tungstenite::protocol::Role::Client, None).await.split();
-            peers.lock().await.insert(addr, write);
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let peers: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let bus: SharedBus = Arc::new(LocalHub::new().join(String::from("node_a")));
+        let peer_id: PeerId = String::from("test_peer");
+        let _peer_rx: Receiver<Message> = test_peer(&storage, &peers, &bus, &peer_id).await;
+
+        let new_room: Room = Room::new(String::from("test_room"), peer_id.clone(), RoomPolicy::default());
+        assert!(storage.insert_room(&new_room).await.unwrap());
+
+        let other_peer_id: PeerId = String::from("other_peer");
+        let mut other_rx: Receiver<Message> = test_peer(&storage, &peers, &bus, &other_peer_id).await;
+        let mut other_room: Room = Room::new(String::from("other_room"), other_peer_id.clone(), RoomPolicy::default());
+        other_room.add_participant(peer_id.clone());
+        assert!(storage.insert_room(&other_room).await.unwrap());
+
+        DataType::close(storage.clone(), peers.clone(), bus.clone(), peer_id.clone()).await;
+
+        assert!(matches!(find_room(&storage, "test_room").await, Err(Error::RoomNotFound)));
+        assert!(peers.read().await.get(&peer_id).is_none());
+        assert_eq!(stored_room(&storage, "other_room").await.members(), vec![other_peer_id]);
+
+        let left_data: Value = next_msg(&mut other_rx);
+        assert_eq!(left_data["data_type"], "peer_left");
+        assert_eq!(left_data["peer_id"], "test_peer");
+    }
+
+    #[tokio::test]
+    async fn test_close_detached() {
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let peers: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let bus: SharedBus = Arc::new(LocalHub::new().join(String::from("node_a")));
+        let peer_id: PeerId = String::from("test_peer");
+        let _peer_rx: Receiver<Message> = test_peer(&storage, &peers, &bus, &peer_id).await;
+        assert!(storage.insert_room(&Room::new(String::from("test_room"), peer_id.clone(), RoomPolicy::default())).await.unwrap());
+
+        // A peer unregistered beforehand keeps its rooms when its connection goes.
+        assert!(storage.remove_peer(&peer_id).await.unwrap());
+        DataType::close(storage.clone(), peers.clone(), bus.clone(), peer_id.clone()).await;
+
+        assert!(peers.read().await.is_empty());
+        assert_eq!(stored_room(&storage, "test_room").await.members(), vec![peer_id]);
+    }
+
+    async fn recv_msg(rx: &mut Receiver<Message>) -> Value {
+        let msg: Message = timeout(Duration::from_secs(5), rx.recv()).await.expect("no message relayed").unwrap();
+        serde_json::from_str(&msg.to_string()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cross_node() {
+        // Two servers sharing the storage, each holding its own connections.
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let hub: LocalHub = LocalHub::new();
+        let peers_a: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let peers_b: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let bus_a: SharedBus = Arc::new(hub.join(String::from("node_a")));
+        let bus_b: SharedBus = Arc::new(hub.join(String::from("node_b")));
+        for (peers, bus) in [(&peers_a, &bus_a), (&peers_b, &bus_b)] {
+            spawn(bus::forward(bus.subscribe().await.unwrap(), storage.clone(), peers.clone(), bus.clone()));
         }
 
-        let new_room: Room = Room { 
-            room_id: String::from("test_room"),
-            offer: Offer { 
-                r#type: String::new(), 
-                sdp: String::new(), 
-            },
-            candidates: Vec::new(),
-        };
-        rooms.lock().await.insert(addr, Arc::new(Mutex::new(new_room.clone())));
+        let owner_id: PeerId = String::from("owner");
+        let joiner_id: PeerId = String::from("joiner");
+        let mut owner_rx: Receiver<Message> = test_peer(&storage, &peers_a, &bus_a, &owner_id).await;
+        let mut joiner_rx: Receiver<Message> = test_peer(&storage, &peers_b, &bus_b, &joiner_id).await;
+        assert_eq!(storage.peer(&joiner_id).await.unwrap().unwrap().node_id, "node_b");
+
+        let mut new_room: Room = Room::new(String::from("test_room"), owner_id.clone(), RoomPolicy::TransferOwnership);
+        new_room.participants[0].offer = Some(test_offer());
+        assert!(storage.insert_room(&new_room).await.unwrap());
+
+        assert!(DataType::join_call(storage.clone(), String::from("test_room"), peers_b.clone(), bus_b.clone(), joiner_id.clone(), None, None).await.is_ok());
+        assert_eq!(next_msg(&mut joiner_rx)["data_type"], "room_members");
+        assert_eq!(next_msg(&mut joiner_rx)["data_type"], "offer");
+        let joined_data: Value = recv_msg(&mut owner_rx).await;
+        assert_eq!(joined_data["data_type"], "peer_joined");
+        assert_eq!(joined_data["peer_id"], "joiner");
 
-        DataType::close(rooms.clone(), peers.clone(), addr).await;
+        assert!(DataType::send_answer(storage.clone(), String::from("test_room"), test_answer(), None, peers_b.clone(), bus_b.clone(), joiner_id.clone()).await.is_ok());
+        assert_eq!(recv_msg(&mut owner_rx).await["data_type"], "answer");
+        let result = DataType::send_candidate(storage.clone(), String::from("test_room"), test_candidate(), Some(joiner_id.clone()), peers_a.clone(), bus_a.clone(), owner_id.clone()).await;
+        assert!(result.is_ok());
+        let candidate_data: Value = recv_msg(&mut joiner_rx).await;
+        assert_eq!(candidate_data["data_type"], "candidate");
+        assert_eq!(candidate_data["from"], "owner");
 
-        assert!(rooms.lock().await.get(&addr).is_none());
-        assert!(peers.lock().await.get(&addr).is_none());
+        // The owner's node hands the room over to the member connected elsewhere.
+        DataType::close(storage.clone(), peers_a.clone(), bus_a.clone(), owner_id.clone()).await;
+        assert_eq!(recv_msg(&mut joiner_rx).await["data_type"], "peer_left");
+        let owner_data: Value = recv_msg(&mut joiner_rx).await;
+        assert_eq!(owner_data["data_type"], "owner_changed");
+        assert_eq!(owner_data["owner"], "joiner");
+        assert_eq!(stored_room(&storage, "test_room").await.owner, Some(joiner_id));
     }
 
     #[tokio::test]
     async fn test_find_room() {
-        let rooms: ChatRooms = Arc::new(Mutex::new(HashMap::new()));
-        let room = Room {
-            room_id: "test_room".to_string(),
-            offer: Offer {
-                r#type: "test_offer".to_string(),
-                sdp: "test_sdp".to_string(),
-            },
-            candidates: vec![Candidate {
-                candidate: "test_candidate".to_string(),
-                sdpMid: "test_sdpMid".to_string(),
-                sdpMLineIndex: 0,
-                usernameFragment: "test_usernameFragment".to_string(),
-            }],
-        };
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-        rooms.lock().await.insert(addr, Arc::new(Mutex::new(room)));
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let peer_id: PeerId = String::from("test_peer");
+        let mut room = Room::new("test_room".to_string(), peer_id.clone(), RoomPolicy::default());
+        room.participants[0].offer = Some(Offer {
+            r#type: "test_offer".to_string(),
+            sdp: "test_sdp".to_string(),
+        });
+        room.participants[0].candidates.push(Candidate {
+            candidate: "test_candidate".to_string(),
+            sdpMid: "test_sdpMid".to_string(),
+            sdpMLineIndex: 0,
+            usernameFragment: "test_usernameFragment".to_string(),
+        });
+        assert!(storage.insert_room(&room).await.unwrap());
 
-        let result = find_room(&rooms, "test_room".to_string()).await;
-        assert!(result.is_some());
-        let binding = result.unwrap();
-        let room = binding.lock().await;
+        let result = find_room(&storage, "test_room").await;
+        assert!(result.is_ok());
+        let room: Room = result.unwrap();
+        let participant = &room.participants[0];
         assert_eq!(room.room_id, "test_room");
-        assert_eq!(room.offer.r#type, "test_offer");
-        assert_eq!(room.offer.sdp, "test_sdp");
-        assert_eq!(room.candidates[0].candidate, "test_candidate");
-        assert_eq!(room.candidates[0].sdpMid, "test_sdpMid");
-        assert_eq!(room.candidates[0].sdpMLineIndex, 0);
-        assert_eq!(room.candidates[0].usernameFragment, "test_usernameFragment");
+        assert_eq!(participant.peer_id, peer_id);
+        assert_eq!(participant.offer.as_ref().unwrap().r#type, "test_offer");
+        assert_eq!(participant.offer.as_ref().unwrap().sdp, "test_sdp");
+        assert_eq!(participant.candidates[0].candidate, "test_candidate");
+        assert_eq!(participant.candidates[0].sdpMid, "test_sdpMid");
+        assert_eq!(participant.candidates[0].sdpMLineIndex, 0);
+        assert_eq!(participant.candidates[0].usernameFragment, "test_usernameFragment");
+    }
+
+    #[test]
+    fn test_room_recipients() {
+        let mut room = Room::new(String::from("test_room"), String::from("peer_a"), RoomPolicy::default());
+        room.add_participant(String::from("peer_b"));
+        room.add_participant(String::from("peer_c"));
+        let peer_a: PeerId = String::from("peer_a");
+
+        assert_eq!(room.recipients(&peer_a, &None), Ok(vec![String::from("peer_b"), String::from("peer_c")]));
+        assert_eq!(room.recipients(&peer_a, &Some(String::from("peer_c"))), Ok(vec![String::from("peer_c")]));
+        assert_eq!(room.recipients(&peer_a, &Some(String::from("peer_x"))), Err(Error::PeerUnreachable(Some(String::from("peer_x")))));
+        assert_eq!(room.recipients(&peer_a, &Some(peer_a.clone())), Err(Error::PeerUnreachable(Some(peer_a.clone()))));
+        assert_eq!(room.recipients(&String::from("peer_x"), &None), Err(Error::NotInRoom(String::from("peer_x"))));
+    }
+
+    #[test]
+    fn test_room_remove_participant() {
+        let mut room = Room::new(String::from("test_room"), String::from("peer_a"), RoomPolicy::default());
+        room.add_participant(String::from("peer_b"));
+
+        assert!(room.remove_participant(&String::from("peer_a")));
+        assert!(!room.remove_participant(&String::from("peer_a")));
+        assert_eq!(room.members(), vec![String::from("peer_b")]);
+    }
+
+    #[test]
+    fn test_signal_message_parse() {
+        let store_room: SignalMessage = serde_json::from_str(r#"{"data_type":"store_room","room_id":"test_room"}"#).unwrap();
+        assert!(matches!(store_room, SignalMessage::StoreRoom { ref room_id, password: None, invite_only: false, default_role: Role::Presenter } if room_id == "test_room"));
+
+        let join_call: SignalMessage = serde_json::from_str(r#"{"data_type":"join_call","room_id":"test_room","password":"secret"}"#).unwrap();
+        assert!(matches!(join_call, SignalMessage::JoinCall { password: Some(ref password), invite_token: None, .. } if password == "secret"));
+
+        let set_role: SignalMessage = serde_json::from_str(r#"{"data_type":"set_role","room_id":"test_room","peer_id":"peer_b","role":"viewer"}"#).unwrap();
+        assert!(matches!(set_role, SignalMessage::SetRole { role: Role::Viewer, .. }));
+
+        let send_answer: SignalMessage = serde_json::from_str(r#"{"data_type":"send_answer","room_id":"test_room","answer":{"type":"answer","sdp":"sdp_answer"},"to":"peer_b"}"#).unwrap();
+        assert_eq!(send_answer.room_id(), "test_room");
+        assert!(matches!(send_answer, SignalMessage::SendAnswer { to: Some(ref to), .. } if to == "peer_b"));
+
+        let send_offer: SignalMessage = serde_json::from_str(r#"{"data_type":"send_offer","room_id":"test_room","offer":{"type":"offer","sdp":"sdp_offer"}}"#).unwrap();
+        assert!(matches!(send_offer, SignalMessage::SendOffer { to: None, .. }));
+
+        assert!(serde_json::from_str::<SignalMessage>(r#"{"data_type":"store_offer","room_id":"test_room"}"#).is_err());
+        assert!(serde_json::from_str::<SignalMessage>(r#"{"data_type":"unknown","room_id":"test_room"}"#).is_err());
+
+        let envelope: SignalEnvelope = serde_json::from_str(r#"{"data_type":"join_call","room_id":"test_room","request_id":"req-1"}"#).unwrap();
+        assert_eq!(envelope.request_id, Some(String::from("req-1")));
+        assert!(matches!(envelope.message, SignalMessage::JoinCall { .. }));
+
+        let ack: Value = envelope.message.ack(String::from("req-1"));
+        assert_eq!(ack["data_type"], "ack");
+        assert_eq!(ack["command"], "join_call");
+        assert_eq!(ack["room_id"], "test_room");
+        assert_eq!(ack["request_id"], "req-1");
+    }
+
+    #[test]
+    fn test_room_depart() {
+        let peer_a: PeerId = String::from("peer_a");
+        let peer_b: PeerId = String::from("peer_b");
+        let peer_c: PeerId = String::from("peer_c");
+
+        let mut room = Room::new(String::from("test_room"), peer_a.clone(), RoomPolicy::Destroy);
+        room.add_participant(peer_b.clone());
+        assert_eq!(room.depart(&peer_c), Departure::NotMember);
+        assert_eq!(room.depart(&peer_a), Departure::Closed);
+
+        let mut room = Room::new(String::from("test_room"), peer_a.clone(), RoomPolicy::TransferOwnership);
+        room.add_participant(peer_b.clone());
+        room.add_participant(peer_c.clone());
+        assert_eq!(room.depart(&peer_a), Departure::OwnerChanged(peer_b.clone()));
+        assert!(room.is_owner(&peer_b));
+        assert_eq!(room.depart(&peer_c), Departure::Left);
+        assert_eq!(room.depart(&peer_b), Departure::Closed);
+
+        let mut room = Room::new(String::from("test_room"), peer_a.clone(), RoomPolicy::KeepUntilEmpty);
+        room.add_participant(peer_b.clone());
+        assert_eq!(room.depart(&peer_a), Departure::Left);
+        assert_eq!(room.owner, None);
+        assert_eq!(room.depart(&peer_b), Departure::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_send_to_dead_peer() {
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let peers: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let bus: SharedBus = Arc::new(LocalHub::new().join(String::from("node_a")));
+        let peer_id: PeerId = String::from("test_peer");
+        let receiver_id: PeerId = String::from("receiver");
+        let dead_id: PeerId = String::from("dead_peer");
+        let mut receiver_rx: Receiver<Message> = test_peer(&storage, &peers, &bus, &receiver_id).await;
+        drop(test_peer(&storage, &peers, &bus, &dead_id).await);
+
+        let mut new_room: Room = Room::new(String::from("test_room"), peer_id.clone(), RoomPolicy::default());
+        new_room.add_participant(dead_id.clone());
+        new_room.add_participant(receiver_id.clone());
+        assert!(storage.insert_room(&new_room).await.unwrap());
+
+        assert!(DataType::send_answer(storage.clone(), String::from("test_room"), test_answer(), None, peers.clone(), bus.clone(), peer_id.clone()).await.is_ok());
+
+        assert_eq!(next_msg(&mut receiver_rx)["data_type"], "answer");
+        let left_data: Value = next_msg(&mut receiver_rx);
+        assert_eq!(left_data["data_type"], "peer_left");
+        assert_eq!(left_data["peer_id"], "dead_peer");
+        assert!(peers.read().await.get(&dead_id).is_none());
+        assert_eq!(stored_room(&storage, "test_room").await.members(), vec![peer_id, receiver_id]);
+    }
+
+    #[test]
+    fn test_room_access() {
+        let owner: PeerId = String::from("owner");
+        let guest: PeerId = String::from("guest");
+        let invited: PeerId = String::from("invited");
+
+        let mut room = Room::new(String::from("test_room"), owner.clone(), RoomPolicy::default());
+        room.access = RoomAccess::new(Some("secret"), false, Role::Viewer);
+        assert!(!room.access.unlocks("wrong"));
+        assert!(room.access.unlocks("secret"));
+        assert_eq!(room.admit(&guest, false, None), Err(Error::AccessDenied));
+        assert_eq!(room.admit(&guest, true, None), Ok(Role::Viewer));
+        assert_eq!(room.admit(&owner, false, None), Ok(Role::Host));
+
+        let mut room = Room::new(String::from("test_room"), owner.clone(), RoomPolicy::default());
+        room.access = RoomAccess::new(None, true, Role::Presenter);
+        assert_eq!(room.create_invite(&guest, Role::Presenter), Err(Error::NotInRoom(guest.clone())));
+        let invite_token: String = room.create_invite(&owner, Role::Viewer).unwrap();
+        assert!(!room.access.unlocks(""));
+        assert_eq!(room.admit(&guest, false, None), Err(Error::AccessDenied));
+        assert_eq!(room.admit(&invited, false, Some(&invite_token)), Ok(Role::Viewer));
+        assert_eq!(room.admit(&guest, false, Some(&invite_token)), Err(Error::AccessDenied));
+        assert_eq!(room.create_invite(&invited, Role::Host), Err(Error::Forbidden(String::from("create invites"))));
+    }
+
+    #[test]
+    fn test_password_hash() {
+        let hash: PasswordHash = PasswordHash::new("secret");
+        assert_eq!(hash.rounds, PASSWORD_ROUNDS);
+        assert!(hash.matches("secret"));
+        assert!(!hash.matches("Secret"));
+
+        let truncated: PasswordHash = PasswordHash { digest: Vec::new(), ..hash };
+        assert!(!truncated.matches("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_room_roles() {
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let peers: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let bus: SharedBus = Arc::new(LocalHub::new().join(String::from("node_a")));
+        let host_id: PeerId = String::from("host");
+        let viewer_id: PeerId = String::from("viewer");
+        let mut host_rx: Receiver<Message> = test_peer(&storage, &peers, &bus, &host_id).await;
+        let mut viewer_rx: Receiver<Message> = test_peer(&storage, &peers, &bus, &viewer_id).await;
+
+        let mut new_room: Room = Room::new(String::from("test_room"), host_id.clone(), RoomPolicy::default());
+        new_room.access = RoomAccess::new(None, false, Role::Viewer);
+        new_room.add_participant(viewer_id.clone());
+        assert!(storage.insert_room(&new_room).await.unwrap());
+
+        let result = DataType::store_offer(storage.clone(), String::from("test_room"), test_offer(), viewer_id.clone()).await;
+        assert_eq!(result, Err(Error::Forbidden(String::from("publish offers"))));
+        let result = DataType::kick(storage.clone(), String::from("test_room"), host_id.clone(), peers.clone(), bus.clone(), viewer_id.clone()).await;
+        assert_eq!(result, Err(Error::Forbidden(String::from("kick members"))));
+
+        assert!(DataType::set_role(storage.clone(), String::from("test_room"), viewer_id.clone(), Role::Presenter, peers.clone(), bus.clone(), host_id.clone()).await.is_ok());
+        assert_eq!(next_msg(&mut host_rx)["data_type"], "role_changed");
+        assert_eq!(next_msg(&mut viewer_rx)["role"], "presenter");
+        assert!(DataType::store_offer(storage.clone(), String::from("test_room"), test_offer(), viewer_id.clone()).await.is_ok());
+
+        let result = DataType::kick(storage.clone(), String::from("test_room"), host_id.clone(), peers.clone(), bus.clone(), host_id.clone()).await;
+        assert_eq!(result.unwrap_err().code(), "forbidden");
+        let result = DataType::kick(storage.clone(), String::from("test_room"), String::from("stranger"), peers.clone(), bus.clone(), host_id.clone()).await;
+        assert_eq!(result.unwrap_err().code(), "peer_unreachable");
+        let mut room: Room = stored_room(&storage, "test_room").await;
+        room.set_role(&host_id, &viewer_id, Role::Host).unwrap();
+        assert_eq!(room.kick(&viewer_id, &host_id).unwrap_err().code(), "forbidden");
+
+        assert!(DataType::kick(storage.clone(), String::from("test_room"), viewer_id.clone(), peers.clone(), bus.clone(), host_id.clone()).await.is_ok());
+        assert_eq!(next_msg(&mut viewer_rx)["data_type"], "kicked");
+        let left_data: Value = next_msg(&mut host_rx);
+        assert_eq!(left_data["data_type"], "peer_left");
+        assert_eq!(left_data["peer_id"], "viewer");
+        assert_eq!(stored_room(&storage, "test_room").await.members(), vec![host_id]);
     }
 }
\ No newline at end of file
@@ -0,0 +1,453 @@
+use futures_util::stream;
+
+use hyper::{
+    Body,
+    Method,
+    Request,
+    Response,
+    StatusCode,
+    body::{to_bytes, Bytes},
+    header::{HeaderValue, ORIGIN}
+};
+use serde_json::{json, Value};
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration
+};
+use tokio::{
+    sync::{mpsc::{channel, Receiver, Sender}, Mutex, OwnedMutexGuard},
+    time::{interval, timeout, Instant}
+};
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tracing::{debug, info, info_span, warn, Instrument, Span};
+use uuid::Uuid;
+
+use crate::config::Settings;
+use crate::logging::redact_message;
+use crate::websocket::auth::Identity;
+use crate::websocket::data_transfer::{register, DataTransfer, DataType};
+use crate::websocket::handler::{authenticate, check_admission, empty_response, json_response, origin_allowed, reject, Rejection};
+use crate::websocket::webrtc::WebRTCStreamTransfer;
+
+use super::HttpSessions;
+use super::OUTBOUND_QUEUE_SIZE;
+use super::Peer;
+use super::PeerId;
+use super::PeerMap;
+use super::Resumptions;
+use super::SharedAuthenticator;
+use super::SharedBus;
+use super::SharedStorage;
+
+/// How long a long-poll request waits for a first message before returning empty.
+const LONG_POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// Interval of the SSE comment lines that keep proxies from timing out an idle stream.
+const SSE_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Name of the query parameter or header carrying the token returned on session creation.
+const SESSION_PARAM: &str = "session";
+const SESSION_HEADER: &str = "X-Session-Token";
+
+/// A peer signaling over plain HTTP. Its outbound queue is read by whichever
+/// `/events` or `/poll` request is currently open.
+pub struct HttpSession {
+    pub peer_id: PeerId,
+    rx: Arc<Mutex<Receiver<Message>>>,
+    last_seen: Instant,
+}
+
+/// HTTP transport for clients whose proxies strip WebSocket upgrades. Routes under
+/// `http_path`:
+///
+/// - `POST /session` creates a peer and returns its `welcome` with a `session_token`
+/// - `POST /send` runs one signaling message, exactly like a WebSocket text frame
+/// - `GET /events` streams outbound messages as Server-Sent Events
+/// - `GET /poll` long-polls for outbound messages and returns them as a JSON array
+/// - `DELETE /session` leaves, like closing the socket
+pub struct HttpFallback;
+impl HttpFallback {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn route(req: Request<Body>, storage: SharedStorage, peers: PeerMap, bus: SharedBus, sessions: HttpSessions, resumptions: Resumptions, addr: SocketAddr, settings: Arc<Settings>, authenticator: SharedAuthenticator) -> Response<Body> {
+        if !origin_allowed(req.headers(), &settings.allowed_origins) {
+            warn!(addr = %addr, origin = ?req.headers().get(ORIGIN), "rejecting: origin is not allowed");
+            return reject(StatusCode::FORBIDDEN, "Origin not allowed").response();
+        }
+        // Only origins named in `allowed_origins` may read responses cross-origin. With an
+        // empty list any page could otherwise read a session opened with the visitor's cookie.
+        let origin: Option<HeaderValue> = match settings.allowed_origins.is_empty() {
+            true => None,
+            false => req.headers().get(ORIGIN).cloned(),
+        };
+        let route: String = req.uri().path()[settings.http_path.len()..].to_string();
+
+        let res: Result<Response<Body>, Rejection> = match (req.method(), route.as_str()) {
+            (&Method::OPTIONS, _) => Ok(preflight()),
+            (&Method::POST, "/session") => Self::open(req, storage, peers, bus, sessions, &resumptions, addr, &authenticator, &settings).await,
+            (&Method::DELETE, "/session") => Self::leave(req, storage, peers, bus, sessions).await,
+            (&Method::POST, "/send") => Self::send(req, storage, peers, bus, sessions, addr, &settings).await,
+            (&Method::GET, "/events") => Self::events(req, storage, peers, bus, sessions).await,
+            (&Method::GET, "/poll") => Self::poll(req, storage, peers, bus, sessions).await,
+            _ => Err(reject(StatusCode::NOT_FOUND, "Not found")),
+        };
+        let mut res: Response<Body> = res.unwrap_or_else(|rejection| rejection.response());
+        if let Some(origin) = origin {
+            let headers = res.headers_mut();
+            headers.insert("Access-Control-Allow-Origin", origin);
+            headers.insert("Access-Control-Allow-Credentials", HeaderValue::from_static("true"));
+            headers.insert("Vary", HeaderValue::from_static("Origin"));
+        }
+        res
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn open(req: Request<Body>, storage: SharedStorage, peers: PeerMap, bus: SharedBus, sessions: HttpSessions, resumptions: &Resumptions, addr: SocketAddr, authenticator: &SharedAuthenticator, settings: &Settings) -> Result<Response<Body>, Rejection> {
+        check_admission(&req, addr, &peers, resumptions, settings).await?;
+        let identity: Option<Identity> = authenticate(&req, addr, authenticator).await?.map(|(identity, _)| identity);
+
+        let peer_id: PeerId = Uuid::new_v4().to_string();
+        let session_token: String = Uuid::new_v4().simple().to_string();
+        let user_id: Option<String> = identity.as_ref().map(|identity| identity.user_id.clone());
+        info!(peer_id = %peer_id, addr = %addr, transport = "http", user_id = user_id.as_deref(), "new connection");
+        let (tx, rx): (Sender<Message>, Receiver<Message>) = channel(OUTBOUND_QUEUE_SIZE);
+
+        register(&storage, &peers, &bus, peer_id.clone(), Peer { addr, tx, identity }).await
+            .map_err(|_| reject(StatusCode::SERVICE_UNAVAILABLE, "Storage unavailable"))?;
+        sessions.write().await.insert(session_token.clone(), HttpSession {
+            peer_id: peer_id.clone(),
+            rx: Arc::new(Mutex::new(rx)),
+            last_seen: Instant::now(),
+        });
+
+        let welcome_data: Value = json!({
+            "data_type": "welcome",
+            "peer_id": peer_id,
+            "user_id": user_id,
+            "session_token": session_token,
+        });
+        Ok(json_response(StatusCode::OK, &welcome_data))
+    }
+
+    async fn leave(req: Request<Body>, storage: SharedStorage, peers: PeerMap, bus: SharedBus, sessions: HttpSessions) -> Result<Response<Body>, Rejection> {
+        let (session_token, peer_id, _) = session(&req, &sessions).await?;
+        close_session(&storage, &peers, &bus, &sessions, &session_token, peer_id).await;
+        Ok(empty_response(StatusCode::NO_CONTENT))
+    }
+
+    async fn send(req: Request<Body>, storage: SharedStorage, peers: PeerMap, bus: SharedBus, sessions: HttpSessions, addr: SocketAddr, settings: &Settings) -> Result<Response<Body>, Rejection> {
+        let (session_token, peer_id, _) = session(&req, &sessions).await?;
+        if !peers.read().await.contains_key(&peer_id) {
+            sessions.write().await.remove(&session_token);
+            return Err(reject(StatusCode::GONE, "The session has ended"));
+        }
+
+        let body: Bytes = to_bytes(req.into_body()).await
+            .map_err(|e| reject(StatusCode::BAD_REQUEST, e.to_string()))?;
+        if body.len() > settings.max_message_size {
+            return Err(reject(StatusCode::PAYLOAD_TOO_LARGE, "The message is too large"));
+        }
+        let msg_string: String = String::from_utf8(body.to_vec())
+            .map_err(|_| reject(StatusCode::BAD_REQUEST, "The message must be UTF-8 JSON"))?;
+
+        let span: Span = info_span!("connection", peer_id = %peer_id, addr = %addr, transport = "http");
+        debug!(parent: &span, payload = %redact_message(&msg_string), "client message");
+        WebRTCStreamTransfer::handle_msg(peers, storage, bus, &peer_id, settings, &msg_string).instrument(span).await;
+        Ok(empty_response(StatusCode::ACCEPTED))
+    }
+
+    async fn events(req: Request<Body>, storage: SharedStorage, peers: PeerMap, bus: SharedBus, sessions: HttpSessions) -> Result<Response<Body>, Rejection> {
+        let (session_token, peer_id, rx) = session(&req, &sessions).await?;
+        let rx: OwnedMutexGuard<Receiver<Message>> = rx.try_lock_owned()
+            .map_err(|_| reject(StatusCode::CONFLICT, "Another request is already reading this session"))?;
+
+        let reader = SessionReader { rx, storage, peers, bus, sessions, session_token, peer_id };
+        let events = stream::unfold(Some(reader), |reader| async move {
+            let mut reader: SessionReader = reader?;
+            loop {
+                let next: Result<Option<Message>, _> = timeout(SSE_KEEPALIVE, reader.rx.recv()).await;
+                touch(&reader.sessions, &reader.session_token).await;
+                match next {
+                    Err(_) => return Some((Ok::<_, Infallible>(Bytes::from(": keep-alive\n\n")), Some(reader))),
+                    Ok(Some(Message::Text(text))) => return Some((Ok(Bytes::from(format!("data: {}\n\n", text))), Some(reader))),
+                    Ok(Some(Message::Close(_))) | Ok(None) => {
+                        reader.close().await;
+                        return Some((Ok(Bytes::from("event: close\ndata: {}\n\n")), None));
+                    },
+                    Ok(Some(_)) => continue,
+                }
+            }
+        });
+
+        let res: Response<Body> = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(Body::wrap_stream(events)).expect("response body error!");
+        Ok(res)
+    }
+
+    async fn poll(req: Request<Body>, storage: SharedStorage, peers: PeerMap, bus: SharedBus, sessions: HttpSessions) -> Result<Response<Body>, Rejection> {
+        let (session_token, peer_id, rx) = session(&req, &sessions).await?;
+        let mut rx: OwnedMutexGuard<Receiver<Message>> = rx.try_lock_owned()
+            .map_err(|_| reject(StatusCode::CONFLICT, "Another request is already reading this session"))?;
+
+        let mut messages: Vec<Value> = Vec::new();
+        let mut closed: bool = false;
+        let mut next: Option<Option<Message>> = timeout(LONG_POLL_TIMEOUT, rx.recv()).await.ok();
+        while let Some(msg) = next {
+            match msg {
+                Some(Message::Text(text)) => messages.push(serde_json::from_str(&text).unwrap_or(Value::String(text))),
+                Some(Message::Close(_)) | None => {
+                    closed = true;
+                    break;
+                },
+                Some(_) => {},
+            }
+            next = rx.try_recv().ok().map(Some);
+        }
+        drop(rx);
+
+        touch(&sessions, &session_token).await;
+        if closed {
+            close_session(&storage, &peers, &bus, &sessions, &session_token, peer_id).await;
+        }
+        Ok(json_response(StatusCode::OK, &Value::Array(messages)))
+    }
+
+    /// Closes sessions that no request has touched for `idle_timeout`, or whose peer
+    /// was already dropped, every half timeout.
+    pub async fn reap_idle(sessions: HttpSessions, peers: PeerMap, bus: SharedBus, storage: SharedStorage, idle_timeout: Duration) {
+        let mut ticker = interval((idle_timeout / 2).max(Duration::from_secs(1)));
+        loop {
+            ticker.tick().await;
+            Self::reap(&sessions, &peers, &bus, &storage, idle_timeout).await;
+        }
+    }
+
+    async fn reap(sessions: &HttpSessions, peers: &PeerMap, bus: &SharedBus, storage: &SharedStorage, idle_timeout: Duration) {
+        let expired: Vec<(String, PeerId)> = {
+            let peers = peers.read().await;
+            sessions.read().await.iter()
+            .filter(|(_, session)| session.last_seen.elapsed() >= idle_timeout || !peers.contains_key(&session.peer_id))
+            .map(|(session_token, session)| (session_token.clone(), session.peer_id.clone()))
+            .collect()
+        };
+        for (session_token, peer_id) in expired {
+            info!(peer_id = %peer_id, "HTTP session expired");
+            close_session(storage, peers, bus, sessions, &session_token, peer_id).await;
+        }
+    }
+}
+
+/// State of an open `/events` stream.
+struct SessionReader {
+    rx: OwnedMutexGuard<Receiver<Message>>,
+    storage: SharedStorage,
+    peers: PeerMap,
+    bus: SharedBus,
+    sessions: HttpSessions,
+    session_token: String,
+    peer_id: PeerId,
+}
+
+impl SessionReader {
+    async fn close(&self) {
+        close_session(&self.storage, &self.peers, &self.bus, &self.sessions, &self.session_token, self.peer_id.clone()).await;
+    }
+}
+
+/// Resolves the request's session token to its peer and outbound queue.
+async fn session(req: &Request<Body>, sessions: &HttpSessions) -> Result<(String, PeerId, Arc<Mutex<Receiver<Message>>>), Rejection> {
+    let from_query: Option<String> = req.uri().query().and_then(|query| {
+        query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == SESSION_PARAM)
+        .map(|(_, session_token)| session_token.to_string())
+    });
+    let session_token: String = from_query
+        .or_else(|| req.headers().get(SESSION_HEADER).and_then(|value| value.to_str().ok()).map(String::from))
+        .ok_or_else(|| reject(StatusCode::UNAUTHORIZED, "Missing session token"))?;
+
+    let mut sessions = sessions.write().await;
+    let session: &mut HttpSession = sessions.get_mut(&session_token)
+        .ok_or_else(|| reject(StatusCode::NOT_FOUND, "Unknown session"))?;
+    session.last_seen = Instant::now();
+    Ok((session_token, session.peer_id.clone(), session.rx.clone()))
+}
+
+async fn touch(sessions: &HttpSessions, session_token: &str) {
+    if let Some(session) = sessions.write().await.get_mut(session_token) {
+        session.last_seen = Instant::now();
+    }
+}
+
+async fn close_session(storage: &SharedStorage, peers: &PeerMap, bus: &SharedBus, sessions: &HttpSessions, session_token: &str, peer_id: PeerId) {
+    sessions.write().await.remove(session_token);
+    DataType::close(storage.clone(), peers.clone(), bus.clone(), peer_id).await;
+}
+
+fn preflight() -> Response<Body> {
+    Response::builder()
+    .status(StatusCode::NO_CONTENT)
+    .header("Access-Control-Allow-Methods", "GET, POST, DELETE, OPTIONS")
+    .header("Access-Control-Allow-Headers", "Authorization, Content-Type, X-Session-Token")
+    .header("Access-Control-Max-Age", "600")
+    .body(Body::empty()).expect("response body error!")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use tokio::{spawn, sync::RwLock};
+
+    use super::*;
+    use crate::websocket::bus::local::LocalHub;
+    use crate::websocket::drain;
+    use crate::websocket::resume::Lease;
+    use crate::websocket::store::memory::MemoryStorage;
+
+    struct TestServer {
+        storage: SharedStorage,
+        peers: PeerMap,
+        bus: SharedBus,
+        sessions: HttpSessions,
+        resumptions: Resumptions,
+        settings: Arc<Settings>,
+    }
+
+    impl TestServer {
+        fn new() -> Self {
+            TestServer {
+                storage: Arc::new(MemoryStorage::new()),
+                peers: Arc::new(RwLock::new(HashMap::new())),
+                bus: Arc::new(LocalHub::new().join(String::from("node_a"))),
+                sessions: Arc::new(RwLock::new(HashMap::new())),
+                resumptions: Arc::new(Mutex::new(HashMap::new())),
+                settings: Arc::new(Settings::default()),
+            }
+        }
+
+        async fn respond(&self, req: Request<Body>) -> Response<Body> {
+            let addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+            HttpFallback::route(
+                req, self.storage.clone(), self.peers.clone(), self.bus.clone(), self.sessions.clone(), self.resumptions.clone(), addr, self.settings.clone(), None
+            ).await
+        }
+
+        async fn request(&self, method: Method, uri: &str, body: &str) -> (StatusCode, Value) {
+            let req: Request<Body> = Request::builder().method(method).uri(uri).body(Body::from(body.to_string())).unwrap();
+            let res: Response<Body> = self.respond(req).await;
+            let status: StatusCode = res.status();
+            let body: Bytes = to_bytes(res.into_body()).await.unwrap();
+            (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_session() {
+        let server: TestServer = TestServer::new();
+        let (status, welcome) = server.request(Method::POST, "/http/session", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(welcome["data_type"], "welcome");
+        let peer_id: &str = welcome["peer_id"].as_str().unwrap();
+        let session_token: &str = welcome["session_token"].as_str().unwrap();
+        assert!(server.peers.read().await.contains_key(peer_id));
+
+        let send_uri: String = format!("/http/send?session={}", session_token);
+        let (status, _) = server.request(Method::POST, &send_uri, r#"{"data_type":"store_room","room_id":"test_room","request_id":"req-1"}"#).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let (status, _) = server.request(Method::POST, &send_uri, r#"{"data_type":"store_room","room_id":"test_room"}"#).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let (status, messages) = server.request(Method::GET, &format!("/http/poll?session={}", session_token), "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(messages[0]["data_type"], "ack");
+        assert_eq!(messages[0]["request_id"], "req-1");
+        assert_eq!(messages[1]["code"], "room_exists");
+
+        let (status, _) = server.request(Method::POST, "/http/send?session=unknown", "{}").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = server.request(Method::GET, "/http/poll", "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _) = server.request(Method::DELETE, &format!("/http/session?session={}", session_token), "").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(server.peers.read().await.is_empty());
+        assert_eq!(server.storage.room_count().await.unwrap(), 0);
+        assert!(server.sessions.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_http_session_reap() {
+        let server: TestServer = TestServer::new();
+        let (_, welcome) = server.request(Method::POST, "/http/session", "").await;
+        let session_token: &str = welcome["session_token"].as_str().unwrap();
+
+        HttpFallback::reap(&server.sessions, &server.peers, &server.bus, &server.storage, Duration::from_secs(60)).await;
+        assert!(server.sessions.read().await.contains_key(session_token));
+
+        HttpFallback::reap(&server.sessions, &server.peers, &server.bus, &server.storage, Duration::ZERO).await;
+        assert!(server.sessions.read().await.is_empty());
+        assert!(server.peers.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_http_admission() {
+        let mut server: TestServer = TestServer::new();
+        server.settings = Arc::new(Settings { max_connections: 1, ..Settings::default() });
+        let (tx, _rx): (Sender<Message>, Receiver<Message>) = channel(OUTBOUND_QUEUE_SIZE);
+        let peer: Peer = Peer { addr: "127.0.0.1:9090".parse().unwrap(), tx, identity: None };
+        register(&server.storage, &server.peers, &server.bus, String::from("peer_1"), peer).await.unwrap();
+        let lease: Lease = Lease::issue(&server.resumptions, String::from("peer_1"), Duration::from_secs(60)).await;
+        assert!(lease.suspend(&server.peers, &server.storage, &server.bus).await.is_some());
+
+        // A suspended peer holds no slot, the live one does.
+        let (status, _) = server.request(Method::POST, "/http/session", "").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = server.request(Method::POST, "/http/session", "").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_http_events_drain() {
+        let server: TestServer = TestServer::new();
+        let (_, welcome) = server.request(Method::POST, "/http/session", "").await;
+        let session_token: &str = welcome["session_token"].as_str().unwrap();
+        let req: Request<Body> = Request::builder().uri(format!("/http/events?session={}", session_token)).body(Body::empty()).unwrap();
+        let res: Response<Body> = server.respond(req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let events = spawn(to_bytes(res.into_body()));
+
+        // The shutdown drain's close frame ends the stream, so the server can stop waiting on it.
+        drain(&server.peers, &server.storage, Duration::from_secs(60)).await;
+        let body: Bytes = timeout(Duration::from_secs(5), events).await.unwrap().unwrap().unwrap();
+        assert!(body.ends_with(b"event: close\ndata: {}\n\n"));
+        assert!(server.peers.read().await.is_empty());
+        assert!(server.sessions.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_http_cors() {
+        let mut server: TestServer = TestServer::new();
+        let preflight = |origin: &str| Request::builder().method(Method::OPTIONS).uri("/http/session").header(ORIGIN, origin).body(Body::empty()).unwrap();
+
+        // Without an allow-list no page may read responses cross-origin.
+        let res: Response<Body> = server.respond(preflight("https://evil.example")).await;
+        assert!(res.headers().get("Access-Control-Allow-Origin").is_none());
+        assert!(res.headers().get("Access-Control-Allow-Credentials").is_none());
+
+        server.settings = Arc::new(Settings { allowed_origins: vec![String::from("https://app.example.com")], ..Settings::default() });
+        let res: Response<Body> = server.respond(preflight("https://app.example.com")).await;
+        assert_eq!(res.headers()["Access-Control-Allow-Origin"], "https://app.example.com");
+        assert_eq!(res.headers()["Access-Control-Allow-Credentials"], "true");
+        // A bearer token is sent in `Authorization`, so the preflight has to allow it.
+        assert!(res.headers()["Access-Control-Allow-Headers"].to_str().unwrap().split(", ").any(|header| header == "Authorization"));
+
+        let res: Response<Body> = server.respond(preflight("https://evil.example")).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(res.headers().get("Access-Control-Allow-Origin").is_none());
+        assert!(res.headers().get("Access-Control-Allow-Credentials").is_none());
+    }
+}
\ No newline at end of file
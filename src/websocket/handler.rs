@@ -1,6 +1,6 @@
 use async_trait::async_trait;
 
-use futures_util::StreamExt;
+use futures_util::{SinkExt, StreamExt};
 
 use hyper::{
     Body, 
@@ -8,23 +8,43 @@ use hyper::{
     Request, 
     Response, 
     StatusCode, 
+    Version, 
+    header::{HeaderMap, HeaderValue, CONNECTION, ORIGIN, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE}, 
     upgrade::{on, Upgraded}
 };
 use std::{
+    collections::VecDeque,
     convert::Infallible,
     net::SocketAddr, 
     sync::Arc
 };
-use tokio::spawn;
+use tokio::{spawn, sync::mpsc::{channel, Receiver, Sender}};
+use serde_json::{json, Value};
 use tokio_tungstenite::{
-    tungstenite::{handshake::derive_accept_key, protocol::Role}, 
+    tungstenite::{handshake::derive_accept_key, protocol::{Message, Role, WebSocketConfig}}, 
     WebSocketStream
 };
+use tracing::{info, info_span, warn, Instrument, Span};
+use uuid::Uuid;
 
+use crate::config::Settings;
+use crate::metrics::{timed_lock, Lock, METRICS};
+use crate::websocket::admin::AdminApi;
+use crate::websocket::auth::{extract_token, AuthError, Identity, TokenSource, BEARER_PROTOCOL};
+use crate::websocket::data_transfer::register;
+use crate::websocket::fallback::HttpFallback;
+use crate::websocket::resume::{self, Lease, RESUME_PARAM};
 use crate::websocket::webrtc::WebRTCStreamTransfer;
 
-use super::ChatRooms;
+use super::HttpSessions;
+use super::OUTBOUND_QUEUE_SIZE;
+use super::Peer;
+use super::PeerId;
 use super::PeerMap;
+use super::Resumptions;
+use super::SharedAuthenticator;
+use super::SharedBus;
+use super::SharedStorage;
 use super::StreamWrite;
 use super::StreamRead;
 
@@ -37,32 +57,141 @@ impl Handler {
 
 #[async_trait]
 pub trait RouterTrait {
-    async fn router(mut self, mut req: Request<Body>, rooms: ChatRooms, peers: PeerMap, addr: SocketAddr) -> Result<Response<Body>, Infallible>;
+    #[allow(clippy::too_many_arguments)]
+    async fn router(mut self, mut req: Request<Body>, storage: SharedStorage, peers: PeerMap, bus: SharedBus, sessions: HttpSessions, resumptions: Resumptions, addr: SocketAddr, settings: Arc<Settings>, authenticator: SharedAuthenticator) -> Result<Response<Body>, Infallible>;
 }
 
 pub struct Router;
 #[async_trait]
 impl RouterTrait for Router {
-    async fn router(mut self, mut req: Request<Body>, rooms: ChatRooms, peers: PeerMap, addr: SocketAddr) -> Result<Response<Body>, Infallible> {            
+    async fn router(mut self, mut req: Request<Body>, storage: SharedStorage, peers: PeerMap, bus: SharedBus, sessions: HttpSessions, resumptions: Resumptions, addr: SocketAddr, settings: Arc<Settings>, authenticator: SharedAuthenticator) -> Result<Response<Body>, Infallible> {            
         match (req.method(), req.uri().path()) {
-            (&Method::GET, "/ws") => {
-                let res_config: Response<Body> = ws_setting(&req); 
+            (&Method::GET, path) if path == settings.ws_path => {
+                if let Err(rejection) = check_admission(&req, addr, &peers, &resumptions, &settings).await {
+                    return Ok(rejection.response());
+                }
+
+                let mut res_config: Response<Body> = match ws_setting(&req) {
+                    Ok(res_config) => res_config,
+                    Err(rejection) => {
+                        warn!(addr = %addr, status = %rejection.status, "rejecting upgrade: {}", rejection.reason);
+                        METRICS.upgrade_failures.inc("bad_handshake");
+                        return Ok(rejection.response());
+                    }
+                };
+
+                let identity: Option<Identity> = match authenticate(&req, addr, &authenticator).await {
+                    Ok(Some((identity, source))) => {
+                        // Browsers drop the socket unless one of the offered subprotocols is echoed back.
+                        if source == TokenSource::Protocol {
+                            res_config.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(BEARER_PROTOCOL));
+                        }
+                        Some(identity)
+                    }
+                    Ok(None) => None,
+                    Err(rejection) => return Ok(rejection.response()),
+                };
+                let reconnect_token: Option<String> = req.uri().query().and_then(|query| {
+                    query.split('&')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(name, _)| *name == RESUME_PARAM)
+                    .map(|(_, reconnect_token)| reconnect_token.to_string())
+                });
                 spawn(async move {
                     match on(&mut req).await {
                         Ok(upgraded) => {                        
-                            println!("New Websocket connection: {}", addr);                                        
-                            let ws_stream: WebSocketStream<Upgraded> = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;    
-                            let (write, read): (StreamWrite, StreamRead) = ws_stream.split();
+                            let user_id: Option<String> = identity.as_ref().map(|identity| identity.user_id.clone());
+                            let ws_stream: WebSocketStream<Upgraded> = WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(ws_config(&settings))).await;    
+                            let (mut write, read): (StreamWrite, StreamRead) = ws_stream.split();
+
+                            let resumed: Option<(PeerId, Lease, VecDeque<Message>, Receiver<Message>)> = match &reconnect_token {
+                                Some(reconnect_token) if !settings.resume_window.is_zero() => {
+                                    Lease::resume(&resumptions, &peers, reconnect_token, &identity, addr, settings.resume_window).await
+                                },
+                                _ => None,
+                            };
+                            if let Some((peer_id, lease, buffered, rx)) = resumed {
+                                let span: Span = info_span!("connection", peer_id = %peer_id, addr = %addr, transport = "websocket");
+                                info!(parent: &span, user_id = user_id.as_deref(), "connection resumed");
+                                // Written ahead of the messages buffered while the peer was away, which
+                                // come before anything queued since.
+                                if let Err(e) = write.send(welcome(&peer_id, &user_id, Some(&lease), true)).await {
+                                    warn!(parent: &span, error = %e, "failed to write the welcome");
+                                }
+                                METRICS.messages_out.inc("welcome");
+                                for msg in buffered {
+                                    if let Err(e) = write.send(msg).await {
+                                        warn!(parent: &span, error = %e, "failed to write a buffered message");
+                                        break;
+                                    }
+                                }
+
+                                spawn(WebRTCStreamTransfer::send_msg(write, rx, peer_id.clone()).instrument(span.clone()));
+                                spawn(WebRTCStreamTransfer::response_msg(peers, storage, bus, read, peer_id, Some(lease), settings).instrument(span));
+                                return;
+                            }
+
+                            let peer_id: PeerId = Uuid::new_v4().to_string();
+                            let span: Span = info_span!("connection", peer_id = %peer_id, addr = %addr, transport = "websocket");
+                            if reconnect_token.is_some() {
+                                info!(parent: &span, "could not resume, starting over");
+                            }
+                            info!(parent: &span, user_id = user_id.as_deref(), "new connection");
+                            let (tx, rx): (Sender<Message>, Receiver<Message>) = channel(OUTBOUND_QUEUE_SIZE);
+                            let lease: Option<Lease> = match settings.resume_window.is_zero() {
+                                true => None,
+                                false => Some(Lease::issue(&resumptions, peer_id.clone(), settings.resume_window).await),
+                            };
 
-                            peers.lock().await.insert(addr, write);
+                            tx.try_send(welcome(&peer_id, &user_id, lease.as_ref(), false)).expect("Fresh outbound queue is full!");
+                            METRICS.messages_out.inc("welcome");
+
+                            if let Err(e) = register(&storage, &peers, &bus, peer_id.clone(), Peer { addr, tx, identity }).await {
+                                warn!(parent: &span, error = %e, "failed to register the connection");
+                                METRICS.upgrade_failures.inc("storage_error");
+                                if let Some(lease) = lease {
+                                    lease.release().await;
+                                }
+                                return;
+                            }
                                 
-                            spawn(WebRTCStreamTransfer::response_msg(Arc::clone(&peers), Arc::clone(&rooms), read, addr));
+                            spawn(WebRTCStreamTransfer::send_msg(write, rx, peer_id.clone()).instrument(span.clone()));
+                            spawn(WebRTCStreamTransfer::response_msg(Arc::clone(&peers), Arc::clone(&storage), Arc::clone(&bus), read, peer_id, lease, settings).instrument(span));
                         }
-                        Err(e) => eprintln!("handle upgrade error: {}", e),
+                        Err(e) => {
+                            warn!(addr = %addr, error = %e, "handle upgrade error");
+                            METRICS.upgrade_failures.inc("upgrade_error");
+                        },
                     }
                 });
                 Ok(res_config)
             },
+
+            (&Method::GET, path) if path == settings.metrics_path => {
+                // Suspended peers stay in the peer map but have no connection.
+                let suspended: usize = resume::suspended(&resumptions).await;
+                let connections: usize = timed_lock(Lock::PeerMap, peers.read()).await.len().saturating_sub(suspended);
+                let open_rooms: usize = match storage.room_count().await {
+                    Ok(open_rooms) => open_rooms,
+                    Err(e) => {
+                        warn!(error = %e, "failed to count rooms for metrics");
+                        return Ok(reject(StatusCode::SERVICE_UNAVAILABLE, "storage unavailable").response());
+                    }
+                };
+                let res: Response<Body> = Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .body(Body::from(METRICS.render(connections, suspended, open_rooms))).expect("response body error!");
+                Ok(res)
+            },
+
+            (_, path) if is_under(path, &settings.http_path) => {
+                Ok(HttpFallback::route(req, storage, peers, bus, sessions, resumptions, addr, settings, authenticator).await)
+            },
+
+            (_, path) if is_under(path, &settings.admin_path) => {
+                Ok(AdminApi::route(req, storage, peers, bus, &settings).await)
+            },
             
             _ => {
                 let mut not_found: Response<Body> = Response::default();
@@ -73,36 +202,238 @@ impl RouterTrait for Router {
     }
 }
 
-fn ws_setting(req: &Request<Body>) -> Response<Body> {
+/// Checks shared by every transport before a new peer is accepted: the connection
+/// limit and the `Origin` allow-list. Suspended peers do not count towards the limit.
+pub(crate) async fn check_admission(req: &Request<Body>, addr: SocketAddr, peers: &PeerMap, resumptions: &Resumptions, settings: &Settings) -> Result<(), Rejection> {
+    let suspended: usize = resume::suspended(resumptions).await;
+    if timed_lock(Lock::PeerMap, peers.read()).await.len().saturating_sub(suspended) >= settings.max_connections {
+        warn!(addr = %addr, max_connections = settings.max_connections, "rejecting: connection limit reached");
+        METRICS.upgrade_failures.inc("server_full");
+        return Err(reject(StatusCode::SERVICE_UNAVAILABLE, "The server is full"));
+    }
+    if !origin_allowed(req.headers(), &settings.allowed_origins) {
+        warn!(addr = %addr, origin = ?req.headers().get(ORIGIN), "rejecting: origin is not allowed");
+        METRICS.upgrade_failures.inc("origin_not_allowed");
+        return Err(reject(StatusCode::FORBIDDEN, "Origin not allowed"));
+    }
+    Ok(())
+}
+
+/// Verifies the request's token when the server requires authentication. `Ok(None)`
+/// means the server accepts anonymous peers.
+pub(crate) async fn authenticate(req: &Request<Body>, addr: SocketAddr, authenticator: &SharedAuthenticator) -> Result<Option<(Identity, TokenSource)>, Rejection> {
+    let authenticator = match authenticator {
+        Some(authenticator) => authenticator,
+        None => return Ok(None),
+    };
+    let verified: Result<(Identity, TokenSource), AuthError> = match extract_token(req) {
+        Some((token, source)) => authenticator.authenticate(&token).await.map(|identity| (identity, source)),
+        None => Err(AuthError::MissingToken),
+    };
+    verified.map(Some).map_err(|e| {
+        warn!(addr = %addr, "rejecting: {}", e);
+        METRICS.upgrade_failures.inc("unauthorized");
+        reject(StatusCode::UNAUTHORIZED, e.to_string())
+    })
+}
+
+/// The first message on a connection. `reconnect_token` is null when resumption is off.
+fn welcome(peer_id: &PeerId, user_id: &Option<String>, lease: Option<&Lease>, resumed: bool) -> Message {
+    let welcome_data: Value = json!({
+        "data_type": "welcome",
+        "peer_id": peer_id,
+        "user_id": user_id,
+        "reconnect_token": lease.map(|lease| &lease.reconnect_token),
+        "resumed": resumed,
+    });
+    Message::Text(serde_json::to_string(&welcome_data).expect("Failed to serialize!"))
+}
+
+fn ws_config(settings: &Settings) -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(settings.max_message_size),
+        max_frame_size: Some(settings.max_message_size),
+        ..WebSocketConfig::default()
+    }
+}
+
+/// Browsers always send `Origin` on a WebSocket upgrade, so once an allow-list is set a
+/// missing or unlisted origin is refused. An empty list allows every origin.
+pub(crate) fn origin_allowed(headers: &HeaderMap, allowed_origins: &[String]) -> bool {
+    if allowed_origins.is_empty() {
+        return true;
+    }
+    match headers.get(ORIGIN).and_then(|origin| origin.to_str().ok()) {
+        Some(origin) => {
+            let origin: &str = origin.trim_end_matches('/');
+            allowed_origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin))
+        }
+        None => false,
+    }
+}
+
+/// Validates an opening handshake against RFC 6455 section 4.2.1 and builds the 101
+/// response. Malformed requests get a 400, clients that don't ask for WebSocket 13 a 426.
+fn ws_setting(req: &Request<Body>) -> Result<Response<Body>, Rejection> {
+    let headers: &HeaderMap = req.headers();
+    if req.version() < Version::HTTP_11 {
+        return Err(reject(StatusCode::BAD_REQUEST, "WebSocket upgrades require HTTP/1.1"));
+    }
+    if !header_has_token(headers, UPGRADE.as_str(), "websocket") {
+        return Err(reject(StatusCode::UPGRADE_REQUIRED, "Upgrade header must be \"websocket\""));
+    }
+    if !header_has_token(headers, CONNECTION.as_str(), "upgrade") {
+        return Err(reject(StatusCode::BAD_REQUEST, "Connection header must include \"Upgrade\""));
+    }
+    if headers.get(SEC_WEBSOCKET_VERSION).map(|version| version.as_bytes()) != Some(b"13") {
+        return Err(reject(StatusCode::UPGRADE_REQUIRED, "Sec-WebSocket-Version must be 13"));
+    }
+    let key: &[u8] = match headers.get(SEC_WEBSOCKET_KEY) {
+        Some(key) if is_valid_key(key.as_bytes()) => key.as_bytes(),
+        _ => return Err(reject(StatusCode::BAD_REQUEST, "Sec-WebSocket-Key must be a base64-encoded 16-byte nonce")),
+    };
+
     let res: Response<Body> = Response::builder()
     .status(StatusCode::SWITCHING_PROTOCOLS)
     .header("Upgrade", "websocket")
     .header("Connection", "Upgrade")
-    .header("Sec-WebSocket-Accept", derive_accept_key(req.headers().get("Sec-WebSocket-Key").expect("Sec-WebSocket-Key error!").as_bytes()))
+    .header("Sec-WebSocket-Accept", derive_accept_key(key))
     .body(Body::empty()).expect("response body error!");
+    Ok(res)
+}
+
+/// A refused request, turned into its error response by [`Rejection::response`].
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Rejection {
+    pub status: StatusCode,
+    pub reason: String,
+}
+
+impl Rejection {
+    pub fn response(&self) -> Response<Body> {
+        let mut builder = Response::builder().status(self.status);
+        if self.status == StatusCode::UPGRADE_REQUIRED {
+            builder = builder
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .header("Sec-WebSocket-Version", "13");
+        }
+        builder.body(Body::from(self.reason.clone())).expect("response body error!")
+    }
+}
+
+pub(crate) fn reject(status: StatusCode, reason: impl Into<String>) -> Rejection {
+    Rejection { status, reason: reason.into() }
+}
+
+pub(crate) fn json_response(status: StatusCode, data: &Value) -> Response<Body> {
+    Response::builder()
+    .status(status)
+    .header("Content-Type", "application/json")
+    .body(Body::from(data.to_string())).expect("response body error!")
+}
+
+pub(crate) fn empty_response(status: StatusCode) -> Response<Body> {
+    let mut res: Response<Body> = Response::default();
+    *res.status_mut() = status;
     res
 }
 
+/// Whether `path` is `prefix` itself or a route below it, e.g. `/http/send` under `/http`.
+fn is_under(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix).is_some_and(|route| route.is_empty() || route.starts_with('/'))
+}
+
+/// Whether any comma-separated value of `name` equals `token`, ignoring case.
+fn header_has_token(headers: &HeaderMap, name: &str, token: &str) -> bool {
+    headers.get_all(name).iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+/// A 16-byte nonce encodes to 22 base64 characters followed by `==`.
+fn is_valid_key(key: &[u8]) -> bool {
+    key.len() == 24
+    && key.ends_with(b"==")
+    && key[..22].iter().all(|c| c.is_ascii_alphanumeric() || *c == b'+' || *c == b'/')
+}
+
 #[cfg(test)]
 mod tests {
     use hyper::Response;
-    use hyper::{Request, Body, StatusCode};
+    use hyper::{Request, Body, StatusCode, Version};
     use tokio_tungstenite::tungstenite::http::HeaderValue;
 
-    use crate::websocket::handler::ws_setting;
+    use crate::websocket::handler::{origin_allowed, ws_setting};
+
+    fn upgrade_request() -> Request<Body> {
+        Request::builder()
+        .uri("/ws")
+        .header("Upgrade", "websocket")
+        .header("Connection", "keep-alive, Upgrade")
+        .header("Sec-WebSocket-Version", "13")
+        .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+        .body(Body::empty()).unwrap()
+    }
 
     #[tokio::test]
     async fn test_response_config_ws_setting() {
-        let mut req: Request<Body> = Request::new(Body::empty());
-        req.headers_mut().insert("Sec-WebSocket-Key", HeaderValue::from_static("test-key"));
+        let req: Request<Body> = upgrade_request();
         
-        let ws_setting: Response<Body> = ws_setting(&req);
+        let ws_setting: Response<Body> = ws_setting(&req).unwrap();
         assert_eq!(ws_setting.status(), StatusCode::SWITCHING_PROTOCOLS);
         assert_eq!(ws_setting.headers().get("Upgrade"), Some(&HeaderValue::from_static("websocket")));
         assert_eq!(ws_setting.headers().get("Connection"), Some(&HeaderValue::from_static("Upgrade")));
+        assert_eq!(ws_setting.headers().get("Sec-WebSocket-Accept"), Some(&HeaderValue::from_static("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")));
 
         let body_bytes = hyper::body::to_bytes(ws_setting.into_body()).await.unwrap().to_vec();
         let vec: Vec<u8> = Vec::new();
         assert_eq!(body_bytes, vec);
     }
+
+    #[tokio::test]
+    async fn test_ws_setting_rejections() {
+        let plain_get: Request<Body> = Request::new(Body::empty());
+        assert_eq!(ws_setting(&plain_get).unwrap_err().status, StatusCode::UPGRADE_REQUIRED);
+
+        let mut no_connection: Request<Body> = upgrade_request();
+        no_connection.headers_mut().remove("Connection");
+        assert_eq!(ws_setting(&no_connection).unwrap_err().status, StatusCode::BAD_REQUEST);
+
+        let mut old_version: Request<Body> = upgrade_request();
+        old_version.headers_mut().insert("Sec-WebSocket-Version", HeaderValue::from_static("8"));
+        let rejection: Response<Body> = ws_setting(&old_version).unwrap_err().response();
+        assert_eq!(rejection.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(rejection.headers().get("Sec-WebSocket-Version"), Some(&HeaderValue::from_static("13")));
+
+        let mut no_key: Request<Body> = upgrade_request();
+        no_key.headers_mut().remove("Sec-WebSocket-Key");
+        assert_eq!(ws_setting(&no_key).unwrap_err().status, StatusCode::BAD_REQUEST);
+
+        let mut bad_key: Request<Body> = upgrade_request();
+        bad_key.headers_mut().insert("Sec-WebSocket-Key", HeaderValue::from_static("test-key"));
+        let rejection: Response<Body> = ws_setting(&bad_key).unwrap_err().response();
+        assert_eq!(rejection.status(), StatusCode::BAD_REQUEST);
+        let body_bytes = hyper::body::to_bytes(rejection.into_body()).await.unwrap();
+        assert_eq!(body_bytes, "Sec-WebSocket-Key must be a base64-encoded 16-byte nonce");
+
+        let mut http_10: Request<Body> = upgrade_request();
+        *http_10.version_mut() = Version::HTTP_10;
+        assert_eq!(ws_setting(&http_10).unwrap_err().status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_origin_allowed() {
+        let allowed: Vec<String> = vec![String::from("https://app.example.com")];
+        let mut req: Request<Body> = upgrade_request();
+        assert!(origin_allowed(req.headers(), &[]));
+        assert!(!origin_allowed(req.headers(), &allowed));
+
+        req.headers_mut().insert("Origin", HeaderValue::from_static("https://APP.example.com"));
+        assert!(origin_allowed(req.headers(), &allowed));
+
+        req.headers_mut().insert("Origin", HeaderValue::from_static("https://evil.example.com"));
+        assert!(!origin_allowed(req.headers(), &allowed));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,117 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::config::Settings;
+
+/// What a peer's read loop should do once [`Keepalive::deadline`] passes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Liveness {
+    /// Queue a ping; the peer has `pong_timeout` to answer it.
+    Ping,
+    /// The peer is gone or silent. Holds the reason for logs and metrics.
+    Expired(&'static str),
+}
+
+/// Liveness timers of one WebSocket peer. Browsers answer pings on their own, so a
+/// missing pong means the connection is dead even when the socket has not errored,
+/// e.g. after a mobile client dropped off the network.
+#[derive(Debug)]
+pub struct Keepalive {
+    ping_interval: Duration,
+    pong_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    next_ping: Instant,
+    pong_due: Option<Instant>,
+    last_message: Instant,
+}
+
+impl Keepalive {
+    pub fn new(settings: &Settings, now: Instant) -> Self {
+        Keepalive {
+            ping_interval: settings.ping_interval,
+            pong_timeout: settings.pong_timeout,
+            idle_timeout: settings.idle_timeout,
+            next_ping: now + settings.ping_interval,
+            pong_due: None,
+            last_message: now,
+        }
+    }
+
+    /// When [`poll`](Keepalive::poll) next has something to do.
+    pub fn deadline(&self) -> Instant {
+        [Some(self.next_ping), self.pong_due, self.idle_timeout.map(|idle_timeout| self.last_message + idle_timeout)]
+            .into_iter()
+            .flatten()
+            .min()
+            .expect("the next ping is always scheduled")
+    }
+
+    /// Any frame from the peer, pongs included, shows the connection is still up.
+    pub fn on_frame(&mut self) {
+        self.pong_due = None;
+    }
+
+    /// A signaling message from the peer, which resets the idle timeout.
+    pub fn on_message(&mut self, now: Instant) {
+        self.on_frame();
+        self.last_message = now;
+    }
+
+    pub fn poll(&mut self, now: Instant) -> Option<Liveness> {
+        if self.pong_due.is_some_and(|pong_due| now >= pong_due) {
+            return Some(Liveness::Expired("pong_timeout"));
+        }
+        if self.idle_timeout.is_some_and(|idle_timeout| now >= self.last_message + idle_timeout) {
+            return Some(Liveness::Expired("idle_timeout"));
+        }
+        if now < self.next_ping {
+            return None;
+        }
+        self.next_ping = now + self.ping_interval;
+        // A ping still waiting for its pong keeps the earlier deadline.
+        self.pong_due.get_or_insert(now + self.pong_timeout);
+        Some(Liveness::Ping)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keepalive_pong() {
+        let settings: Settings = Settings { ping_interval: Duration::from_secs(20), pong_timeout: Duration::from_secs(10), ..Settings::default() };
+        let start: Instant = Instant::now();
+        let mut keepalive: Keepalive = Keepalive::new(&settings, start);
+        assert_eq!(keepalive.deadline(), start + Duration::from_secs(20));
+        assert_eq!(keepalive.poll(start + Duration::from_secs(5)), None);
+
+        assert_eq!(keepalive.poll(start + Duration::from_secs(20)), Some(Liveness::Ping));
+        assert_eq!(keepalive.deadline(), start + Duration::from_secs(30));
+        keepalive.on_frame();
+        assert_eq!(keepalive.deadline(), start + Duration::from_secs(40));
+
+        assert_eq!(keepalive.poll(start + Duration::from_secs(40)), Some(Liveness::Ping));
+        assert_eq!(keepalive.poll(start + Duration::from_secs(49)), None);
+        assert_eq!(keepalive.poll(start + Duration::from_secs(50)), Some(Liveness::Expired("pong_timeout")));
+    }
+
+    #[test]
+    fn test_keepalive_idle() {
+        let settings: Settings = Settings { idle_timeout: Some(Duration::from_secs(30)), ..Settings::default() };
+        let start: Instant = Instant::now();
+        let mut keepalive: Keepalive = Keepalive::new(&settings, start);
+
+        // Answering pings is not enough to stay connected, only messages are.
+        assert_eq!(keepalive.poll(start + Duration::from_secs(20)), Some(Liveness::Ping));
+        keepalive.on_frame();
+        assert_eq!(keepalive.deadline(), start + Duration::from_secs(30));
+        assert_eq!(keepalive.poll(start + Duration::from_secs(30)), Some(Liveness::Expired("idle_timeout")));
+
+        keepalive.on_message(start + Duration::from_secs(30));
+        assert_eq!(keepalive.poll(start + Duration::from_secs(40)), Some(Liveness::Ping));
+        keepalive.on_frame();
+        assert_eq!(keepalive.poll(start + Duration::from_secs(59)), None);
+        assert_eq!(keepalive.poll(start + Duration::from_secs(60)), Some(Liveness::Expired("idle_timeout")));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,323 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::spawn;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::metrics::{timed_lock, Lock, METRICS};
+use crate::websocket::auth::Identity;
+use crate::websocket::data_transfer::{DataTransfer, DataType};
+
+use super::OUTBOUND_QUEUE_SIZE;
+use super::PeerId;
+use super::PeerMap;
+use super::Resumptions;
+use super::SharedBus;
+use super::SharedStorage;
+
+/// Name of the query parameter a reconnecting client passes its reconnect token in.
+pub const RESUME_PARAM: &str = "reconnect_token";
+
+/// Messages kept for a suspended peer. Beyond this the oldest ones are dropped.
+pub const SUSPEND_BUFFER_SIZE: usize = 64;
+
+/// A WebSocket peer that a new connection can take over with its reconnect token. While
+/// it has no connection, messages for it are collected by the `suspended` task.
+pub struct Resumable {
+    peer_id: PeerId,
+    suspended: Option<JoinHandle<VecDeque<Message>>>,
+}
+
+/// What a WebSocket read loop holds its peer by. Each connection gets a new reconnect
+/// token, so once the peer was resumed elsewhere the old connection's lease is stale and
+/// ending that connection leaves the peer alone.
+pub struct Lease {
+    resumptions: Resumptions,
+    pub reconnect_token: String,
+    window: Duration,
+}
+
+impl Lease {
+    /// Makes a newly connected peer resumable for `window` after its connection drops.
+    pub async fn issue(resumptions: &Resumptions, peer_id: PeerId, window: Duration) -> Lease {
+        let reconnect_token: String = Uuid::new_v4().simple().to_string();
+        resumptions.lock().await.insert(reconnect_token.clone(), Resumable { peer_id, suspended: None });
+        Lease { resumptions: resumptions.clone(), reconnect_token, window }
+    }
+
+    /// Moves the peer behind `reconnect_token` onto a new connection from `addr`. Returns
+    /// its id, the new connection's lease, the last [`SUSPEND_BUFFER_SIZE`] messages
+    /// buffered while the peer was suspended, and the queue its writer reads once those
+    /// are written. `None` when the token is unknown or expired, or was issued to
+    /// another user.
+    pub async fn resume(
+        resumptions: &Resumptions,
+        peers: &PeerMap,
+        reconnect_token: &str,
+        identity: &Option<Identity>,
+        addr: SocketAddr,
+        window: Duration,
+    ) -> Option<(PeerId, Lease, VecDeque<Message>, Receiver<Message>)> {
+        let (peer_id, lease, collector, rx) = {
+            let mut registry = resumptions.lock().await;
+            let resumable: Resumable = registry.remove(reconnect_token)?;
+            let mut peers = timed_lock(Lock::PeerMap, peers.write()).await;
+            // Gone when the peer was closed some other way, e.g. kicked by an administrator.
+            let peer = peers.get_mut(&resumable.peer_id)?;
+            let user_id = |identity: &Option<Identity>| identity.as_ref().map(|identity| identity.user_id.clone());
+            if user_id(&peer.identity) != user_id(identity) {
+                warn!(peer_id = %resumable.peer_id, addr = %addr, "rejecting resumption by another user");
+                registry.insert(reconnect_token.to_string(), resumable);
+                return None;
+            }
+
+            // Replacing the sender ends the old connection's writer, or the suspended
+            // peer's collector, which then hands over what it buffered.
+            let (tx, rx): (Sender<Message>, Receiver<Message>) = channel(OUTBOUND_QUEUE_SIZE);
+            peer.tx = tx;
+            peer.addr = addr;
+            peer.identity = identity.clone();
+
+            let lease: Lease = Lease {
+                resumptions: resumptions.clone(),
+                reconnect_token: Uuid::new_v4().simple().to_string(),
+                window,
+            };
+            registry.insert(lease.reconnect_token.clone(), Resumable { peer_id: resumable.peer_id.clone(), suspended: None });
+            (resumable.peer_id, lease, resumable.suspended, rx)
+        };
+
+        // Awaited after releasing the locks, so messages for other peers are not held up.
+        let buffered: VecDeque<Message> = match collector {
+            Some(collector) => collector.await.unwrap_or_default(),
+            None => VecDeque::new(),
+        };
+        Some((peer_id, lease, buffered, rx))
+    }
+
+    /// Holds the peer after its connection dropped without a close frame. Its rooms are
+    /// kept and its last [`SUSPEND_BUFFER_SIZE`] messages buffered for `window`, after
+    /// which it is closed like any other peer. Returns the dropped connection's sender,
+    /// or `None` when the lease is stale or the peer is gone.
+    pub async fn suspend(&self, peers: &PeerMap, storage: &SharedStorage, bus: &SharedBus) -> Option<Sender<Message>> {
+        let mut registry = self.resumptions.lock().await;
+        let resumable: &mut Resumable = registry.get_mut(&self.reconnect_token)?;
+        if resumable.suspended.is_some() {
+            return None;
+        }
+        let mut peers_guard = timed_lock(Lock::PeerMap, peers.write()).await;
+        let Some(peer) = peers_guard.get_mut(&resumable.peer_id) else {
+            registry.remove(&self.reconnect_token);
+            return None;
+        };
+
+        let (tx, rx): (Sender<Message>, Receiver<Message>) = channel(OUTBOUND_QUEUE_SIZE);
+        let dropped: Sender<Message> = std::mem::replace(&mut peer.tx, tx);
+        resumable.suspended = Some(spawn(collect(rx, resumable.peer_id.clone())));
+        info!(peer_id = %resumable.peer_id, window = ?self.window, "connection lost, holding the peer for resumption");
+
+        spawn(expire(
+            self.resumptions.clone(),
+            peers.clone(),
+            storage.clone(),
+            bus.clone(),
+            self.reconnect_token.clone(),
+            self.window,
+        ));
+        Some(dropped)
+    }
+
+    /// Gives up resumption when the connection was closed on purpose. Returns whether the
+    /// lease was still current, i.e. whether the peer should be closed.
+    pub async fn release(&self) -> bool {
+        self.resumptions.lock().await.remove(&self.reconnect_token).is_some()
+    }
+}
+
+/// Buffers the messages for a suspended peer until its sender is dropped, keeping the
+/// last [`SUSPEND_BUFFER_SIZE`]. Dropped ones count as `resume_overflow` send failures.
+async fn collect(mut rx: Receiver<Message>, peer_id: PeerId) -> VecDeque<Message> {
+    let mut buffered: VecDeque<Message> = VecDeque::with_capacity(SUSPEND_BUFFER_SIZE);
+    let mut overflowed: bool = false;
+    while let Some(msg) = rx.recv().await {
+        if buffered.len() == SUSPEND_BUFFER_SIZE {
+            buffered.pop_front();
+            if !overflowed {
+                warn!(peer_id = %peer_id, "resume buffer is full, dropping the oldest messages");
+                overflowed = true;
+            }
+            METRICS.send_failures.inc("resume_overflow");
+        }
+        buffered.push_back(msg);
+    }
+    buffered
+}
+
+/// Closes a suspended peer once its window is over, unless it was resumed meanwhile.
+async fn expire(resumptions: Resumptions, peers: PeerMap, storage: SharedStorage, bus: SharedBus, reconnect_token: String, window: Duration) {
+    sleep(window).await;
+    let Some(resumable) = resumptions.lock().await.remove(&reconnect_token) else {
+        return;
+    };
+    info!(peer_id = %resumable.peer_id, "resumption window over");
+    DataType::close(storage, peers, bus, resumable.peer_id).await;
+}
+
+/// How many peers are suspended, waiting for their client to reconnect.
+pub async fn suspended(resumptions: &Resumptions) -> usize {
+    resumptions.lock().await.values().filter(|resumable| resumable.suspended.is_some()).count()
+}
+
+/// Drops the suspended peers, which have no connection a shutdown could close, from
+/// `peers`. Like the drained peers, they keep their rooms and their records, until the
+/// next node to start reaps them.
+pub async fn discard(resumptions: &Resumptions, peers: &PeerMap) {
+    let suspended: Vec<PeerId> = {
+        let mut registry = resumptions.lock().await;
+        let reconnect_tokens: Vec<String> = registry.iter()
+            .filter(|(_, resumable)| resumable.suspended.is_some())
+            .map(|(reconnect_token, _)| reconnect_token.clone())
+            .collect();
+        reconnect_tokens.iter().filter_map(|reconnect_token| registry.remove(reconnect_token)).map(|resumable| resumable.peer_id).collect()
+    };
+    let mut peers = timed_lock(Lock::PeerMap, peers.write()).await;
+    for peer_id in suspended {
+        peers.remove(&peer_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::sync::{Mutex, RwLock};
+
+    use super::*;
+    use crate::websocket::bus::local::LocalHub;
+    use crate::websocket::data_transfer::{find_room, register, send_to, Room, RoomPolicy};
+    use crate::websocket::store::memory::MemoryStorage;
+    use crate::websocket::Peer;
+
+    struct TestServer {
+        storage: SharedStorage,
+        peers: PeerMap,
+        bus: SharedBus,
+        resumptions: Resumptions,
+    }
+
+    impl TestServer {
+        fn new() -> Self {
+            TestServer {
+                storage: Arc::new(MemoryStorage::new()),
+                peers: Arc::new(RwLock::new(HashMap::new())),
+                bus: Arc::new(LocalHub::new().join(String::from("node_a"))),
+                resumptions: Arc::new(Mutex::new(HashMap::new())),
+            }
+        }
+
+        async fn connect(&self, peer_id: &str, window: Duration) -> (Lease, Receiver<Message>) {
+            let (tx, rx): (Sender<Message>, Receiver<Message>) = channel(OUTBOUND_QUEUE_SIZE);
+            let peer: Peer = Peer { addr: "127.0.0.1:8080".parse().unwrap(), tx, identity: None };
+            register(&self.storage, &self.peers, &self.bus, peer_id.to_string(), peer).await.unwrap();
+            (Lease::issue(&self.resumptions, peer_id.to_string(), window).await, rx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resume_suspended() {
+        let server: TestServer = TestServer::new();
+        let addr: SocketAddr = "127.0.0.1:9090".parse().unwrap();
+        let (lease, _rx) = server.connect("peer_1", Duration::from_secs(60)).await;
+        assert!(server.storage.insert_room(&Room::new(String::from("test_room"), String::from("peer_1"), RoomPolicy::default())).await.unwrap());
+
+        assert!(lease.suspend(&server.peers, &server.storage, &server.bus).await.is_some());
+        assert!(lease.suspend(&server.peers, &server.storage, &server.bus).await.is_none());
+        assert_eq!(suspended(&server.resumptions).await, 1);
+        assert!(send_to(server.peers.clone(), &[String::from("peer_1")], Message::Text(String::from("during the gap"))).await.is_empty());
+
+        let stranger: Option<Identity> = Some(Identity { user_id: String::from("mallory") });
+        assert!(Lease::resume(&server.resumptions, &server.peers, &lease.reconnect_token, &stranger, addr, Duration::from_secs(60)).await.is_none());
+        let (peer_id, resumed, buffered, _rx) = Lease::resume(&server.resumptions, &server.peers, &lease.reconnect_token, &None, addr, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(peer_id, "peer_1");
+        assert_ne!(resumed.reconnect_token, lease.reconnect_token);
+        assert_eq!(suspended(&server.resumptions).await, 0);
+        assert_eq!(buffered, [Message::Text(String::from("during the gap"))]);
+        assert_eq!(server.peers.read().await.get("peer_1").unwrap().addr, addr);
+        assert_eq!(find_room(&server.storage, "test_room").await.unwrap().members(), vec![peer_id]);
+
+        // The old connection ending late leaves the resumed peer alone.
+        assert!(lease.suspend(&server.peers, &server.storage, &server.bus).await.is_none());
+        assert!(!lease.release().await);
+        assert!(resumed.release().await);
+    }
+
+    #[tokio::test]
+    async fn test_resume_overflow() {
+        let server: TestServer = TestServer::new();
+        let (lease, _rx) = server.connect("peer_1", Duration::from_secs(60)).await;
+        assert!(lease.suspend(&server.peers, &server.storage, &server.bus).await.is_some());
+
+        let overflow: usize = 3;
+        for n in 0..SUSPEND_BUFFER_SIZE + overflow {
+            assert!(send_to(server.peers.clone(), &[String::from("peer_1")], Message::Text(n.to_string())).await.is_empty());
+            // Lets the collector drain the queue, as it would on the multi-threaded runtime.
+            tokio::task::yield_now().await;
+        }
+
+        // The oldest messages are dropped, the peer is kept.
+        let addr: SocketAddr = "127.0.0.1:9090".parse().unwrap();
+        let (_, _resumed, buffered, mut rx) = Lease::resume(&server.resumptions, &server.peers, &lease.reconnect_token, &None, addr, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(buffered, (overflow..SUSPEND_BUFFER_SIZE + overflow).map(|n| Message::Text(n.to_string())).collect::<VecDeque<Message>>());
+        assert!(rx.try_recv().is_err());
+        assert!(send_to(server.peers.clone(), &[String::from("peer_1")], Message::Text(String::from("after"))).await.is_empty());
+        assert_eq!(rx.try_recv().unwrap(), Message::Text(String::from("after")));
+    }
+
+    #[tokio::test]
+    async fn test_resume_live() {
+        let server: TestServer = TestServer::new();
+        let (lease, mut old_rx) = server.connect("peer_1", Duration::from_secs(60)).await;
+
+        // A client reconnecting before the server noticed the drop takes the peer over.
+        let addr: SocketAddr = "127.0.0.1:9090".parse().unwrap();
+        let (_, _resumed, _, mut rx) = Lease::resume(&server.resumptions, &server.peers, &lease.reconnect_token, &None, addr, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(old_rx.recv().await, None);
+        assert!(send_to(server.peers.clone(), &[String::from("peer_1")], Message::Text(String::from("hello"))).await.is_empty());
+        assert_eq!(rx.try_recv().unwrap(), Message::Text(String::from("hello")));
+    }
+
+    #[tokio::test]
+    async fn test_resume_expired() {
+        let server: TestServer = TestServer::new();
+        let (lease, _rx) = server.connect("peer_1", Duration::from_millis(20)).await;
+        assert!(server.storage.insert_room(&Room::new(String::from("test_room"), String::from("peer_1"), RoomPolicy::default())).await.unwrap());
+
+        assert!(lease.suspend(&server.peers, &server.storage, &server.bus).await.is_some());
+        sleep(Duration::from_millis(200)).await;
+
+        assert!(server.peers.read().await.is_empty());
+        assert_eq!(server.storage.peer("peer_1").await.unwrap(), None);
+        assert_eq!(server.storage.room_count().await.unwrap(), 0);
+        let addr: SocketAddr = "127.0.0.1:9090".parse().unwrap();
+        assert!(Lease::resume(&server.resumptions, &server.peers, &lease.reconnect_token, &None, addr, Duration::from_secs(60)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_discard() {
+        let server: TestServer = TestServer::new();
+        let (suspended, _rx) = server.connect("peer_1", Duration::from_secs(60)).await;
+        let (_connected, _rx) = server.connect("peer_2", Duration::from_secs(60)).await;
+        assert!(suspended.suspend(&server.peers, &server.storage, &server.bus).await.is_some());
+
+        discard(&server.resumptions, &server.peers).await;
+        assert!(!server.peers.read().await.contains_key("peer_1"));
+        assert!(server.peers.read().await.contains_key("peer_2"));
+        assert_eq!(server.resumptions.lock().await.len(), 1);
+        assert!(server.storage.peer("peer_1").await.unwrap().is_some());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,131 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::metrics::{timed_lock, Lock};
+use crate::websocket::data_transfer::Room;
+use crate::websocket::store::{PeerRecord, Storage, StoreError, Versioned};
+
+use super::NodeId;
+use super::PeerId;
+use super::RoomId;
+
+#[derive(Default)]
+struct State {
+    rooms: HashMap<RoomId, Versioned<Room>>,
+    peers: HashMap<PeerId, PeerRecord>,
+}
+
+/// Rooms and peers in the process itself. The default, and what tests run against.
+pub struct MemoryStorage {
+    state: Mutex<State>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage { state: Mutex::new(State::default()) }
+    }
+
+    async fn state(&self) -> MutexGuard<'_, State> {
+        timed_lock(Lock::MemoryStore, self.state.lock()).await
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage::new()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn insert_room(&self, room: &Room) -> Result<bool, StoreError> {
+        let mut state = self.state().await;
+        if state.rooms.contains_key(&room.room_id) {
+            return Ok(false);
+        }
+        state.rooms.insert(room.room_id.clone(), Versioned { value: room.clone(), version: 1 });
+        Ok(true)
+    }
+
+    async fn room(&self, room_id: &str) -> Result<Option<Versioned<Room>>, StoreError> {
+        Ok(self.state().await.rooms.get(room_id).cloned())
+    }
+
+    async fn replace_room(&self, room: &Room, version: u64) -> Result<bool, StoreError> {
+        match self.state().await.rooms.get_mut(&room.room_id) {
+            Some(stored) if stored.version == version => {
+                *stored = Versioned { value: room.clone(), version: version + 1 };
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+
+    async fn remove_room(&self, room_id: &str, version: u64) -> Result<bool, StoreError> {
+        let mut state = self.state().await;
+        match state.rooms.get(room_id) {
+            Some(stored) if stored.version == version => {
+                state.rooms.remove(room_id);
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+
+    async fn rooms(&self) -> Result<Vec<Room>, StoreError> {
+        Ok(self.state().await.rooms.values().map(|room| room.value.clone()).collect())
+    }
+
+    async fn room_count(&self) -> Result<usize, StoreError> {
+        Ok(self.state().await.rooms.len())
+    }
+
+    async fn rooms_of(&self, peer_id: &str) -> Result<Vec<RoomId>, StoreError> {
+        let peer_id: PeerId = peer_id.to_string();
+        Ok(self.state().await.rooms.values()
+            .filter(|room| room.value.is_member(&peer_id))
+            .map(|room| room.value.room_id.clone())
+            .collect())
+    }
+
+    async fn insert_peer(&self, peer: &PeerRecord) -> Result<(), StoreError> {
+        self.state().await.peers.insert(peer.peer_id.clone(), peer.clone());
+        Ok(())
+    }
+
+    async fn remove_peer(&self, peer_id: &str) -> Result<bool, StoreError> {
+        Ok(self.state().await.peers.remove(peer_id).is_some())
+    }
+
+    async fn peer(&self, peer_id: &str) -> Result<Option<PeerRecord>, StoreError> {
+        Ok(self.state().await.peers.get(peer_id).cloned())
+    }
+
+    async fn nodes(&self) -> Result<Vec<NodeId>, StoreError> {
+        let mut nodes: Vec<NodeId> = self.state().await.peers.values().map(|peer| peer.node_id.clone()).collect();
+        nodes.sort();
+        nodes.dedup();
+        Ok(nodes)
+    }
+
+    async fn peers_on(&self, node_id: &str) -> Result<Vec<PeerId>, StoreError> {
+        Ok(self.state().await.peers.values()
+            .filter(|peer| peer.node_id == node_id)
+            .map(|peer| peer.peer_id.clone())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::websocket::store::conformance;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_memory_storage() {
+        conformance::run(Arc::new(MemoryStorage::new())).await;
+    }
+}
\ No newline at end of file
@@ -0,0 +1,251 @@
+use ::redis::{aio::ConnectionManager, AsyncCommands, Client, RedisError, Script};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+
+use crate::websocket::data_transfer::Room;
+use crate::websocket::store::{from_json, to_json, PeerRecord, Storage, StoreError, Versioned};
+
+use super::NodeId;
+use super::PeerId;
+use super::RoomId;
+
+/// Every key this backend writes starts with this prefix.
+const KEY_PREFIX: &str = "signaling";
+
+/// Adds a room unless it exists, and indexes it under each member.
+/// KEYS: room hash, room set. ARGV: room id, room JSON, member index prefix.
+const INSERT_ROOM: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return 0
+end
+redis.call('HSET', KEYS[1], 'version', 1, 'room', ARGV[2])
+redis.call('SADD', KEYS[2], ARGV[1])
+for _, participant in ipairs(cjson.decode(ARGV[2]).participants) do
+    redis.call('SADD', ARGV[3] .. participant.peer_id, ARGV[1])
+end
+return 1
+";
+
+/// Replaces a room still at the expected version and moves its member index along.
+/// KEYS: room hash. ARGV: room id, room JSON, version, member index prefix.
+const REPLACE_ROOM: &str = r"
+if redis.call('HGET', KEYS[1], 'version') ~= ARGV[3] then
+    return 0
+end
+for _, participant in ipairs(cjson.decode(redis.call('HGET', KEYS[1], 'room')).participants) do
+    redis.call('SREM', ARGV[4] .. participant.peer_id, ARGV[1])
+end
+for _, participant in ipairs(cjson.decode(ARGV[2]).participants) do
+    redis.call('SADD', ARGV[4] .. participant.peer_id, ARGV[1])
+end
+redis.call('HSET', KEYS[1], 'version', tonumber(ARGV[3]) + 1, 'room', ARGV[2])
+return 1
+";
+
+/// Removes a room still at the expected version along with its member index entries.
+/// KEYS: room hash, room set. ARGV: room id, version, member index prefix.
+const REMOVE_ROOM: &str = r"
+if redis.call('HGET', KEYS[1], 'version') ~= ARGV[2] then
+    return 0
+end
+for _, participant in ipairs(cjson.decode(redis.call('HGET', KEYS[1], 'room')).participants) do
+    redis.call('SREM', ARGV[3] .. participant.peer_id, ARGV[1])
+end
+redis.call('DEL', KEYS[1])
+redis.call('SREM', KEYS[2], ARGV[1])
+return 1
+";
+
+/// Removes a peer and its entry in the index of its node's peers.
+/// KEYS: peer key. ARGV: peer id, node index prefix.
+const REMOVE_PEER: &str = r"
+local peer = redis.call('GET', KEYS[1])
+if not peer then
+    return 0
+end
+redis.call('DEL', KEYS[1])
+redis.call('SREM', ARGV[2] .. (cjson.decode(peer).node_id or ''), ARGV[1])
+return 1
+";
+
+/// Rooms and peers in Redis or a compatible server such as Valkey or KeyDB. Each room
+/// is a hash holding its version and JSON; conditional writes run as Lua scripts so the
+/// version check and the member index stay atomic. The scripts touch index keys they
+/// compute themselves, so Redis Cluster is not supported.
+pub struct RedisStorage {
+    conn: ConnectionManager,
+    insert_room: Script,
+    replace_room: Script,
+    remove_room: Script,
+    remove_peer: Script,
+}
+
+impl RedisStorage {
+    /// Connects to `url`, e.g. `redis://:password@localhost:6379/0`. The connection is
+    /// re-established on its own after failures.
+    pub async fn connect(url: &str) -> Result<RedisStorage, StoreError> {
+        let conn: ConnectionManager = ConnectionManager::new(Client::open(url)?).await?;
+        Ok(RedisStorage {
+            conn,
+            insert_room: Script::new(INSERT_ROOM),
+            replace_room: Script::new(REPLACE_ROOM),
+            remove_room: Script::new(REMOVE_ROOM),
+            remove_peer: Script::new(REMOVE_PEER),
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for RedisStorage {
+    async fn insert_room(&self, room: &Room) -> Result<bool, StoreError> {
+        let inserted: bool = self.insert_room
+            .key(room_key(&room.room_id))
+            .key(rooms_key())
+            .arg(&room.room_id)
+            .arg(to_json(room))
+            .arg(peer_rooms_key(""))
+            .invoke_async(&mut self.conn.clone())
+            .await?;
+        Ok(inserted)
+    }
+
+    async fn room(&self, room_id: &str) -> Result<Option<Versioned<Room>>, StoreError> {
+        let (version, room): (Option<u64>, Option<String>) = self.conn.clone().hget(room_key(room_id), &["version", "room"]).await?;
+        match (version, room) {
+            (Some(version), Some(room)) => Ok(Some(Versioned { value: from_json(&room)?, version })),
+            _ => Ok(None),
+        }
+    }
+
+    async fn replace_room(&self, room: &Room, version: u64) -> Result<bool, StoreError> {
+        let replaced: bool = self.replace_room
+            .key(room_key(&room.room_id))
+            .arg(&room.room_id)
+            .arg(to_json(room))
+            .arg(version)
+            .arg(peer_rooms_key(""))
+            .invoke_async(&mut self.conn.clone())
+            .await?;
+        Ok(replaced)
+    }
+
+    async fn remove_room(&self, room_id: &str, version: u64) -> Result<bool, StoreError> {
+        let removed: bool = self.remove_room
+            .key(room_key(room_id))
+            .key(rooms_key())
+            .arg(room_id)
+            .arg(version)
+            .arg(peer_rooms_key(""))
+            .invoke_async(&mut self.conn.clone())
+            .await?;
+        Ok(removed)
+    }
+
+    async fn rooms(&self) -> Result<Vec<Room>, StoreError> {
+        let mut conn: ConnectionManager = self.conn.clone();
+        let room_ids: Vec<RoomId> = conn.smembers(rooms_key()).await?;
+        if room_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut pipe = ::redis::pipe();
+        for room_id in room_ids.iter() {
+            pipe.hget(room_key(room_id), "room");
+        }
+        // Rooms removed since the set was read come back empty.
+        let rooms: Vec<Option<String>> = pipe.query_async(&mut conn).await?;
+        rooms.into_iter().flatten().map(|room| from_json(&room)).collect()
+    }
+
+    async fn room_count(&self) -> Result<usize, StoreError> {
+        Ok(self.conn.clone().scard(rooms_key()).await?)
+    }
+
+    async fn rooms_of(&self, peer_id: &str) -> Result<Vec<RoomId>, StoreError> {
+        Ok(self.conn.clone().smembers(peer_rooms_key(peer_id)).await?)
+    }
+
+    async fn insert_peer(&self, peer: &PeerRecord) -> Result<(), StoreError> {
+        Ok(::redis::pipe()
+            .atomic()
+            .set(peer_key(&peer.peer_id), to_json(peer)).ignore()
+            .sadd(node_peers_key(&peer.node_id), &peer.peer_id).ignore()
+            .query_async(&mut self.conn.clone())
+            .await?)
+    }
+
+    async fn remove_peer(&self, peer_id: &str) -> Result<bool, StoreError> {
+        let removed: bool = self.remove_peer
+            .key(peer_key(peer_id))
+            .arg(peer_id)
+            .arg(node_peers_key(""))
+            .invoke_async(&mut self.conn.clone())
+            .await?;
+        Ok(removed)
+    }
+
+    async fn peer(&self, peer_id: &str) -> Result<Option<PeerRecord>, StoreError> {
+        let peer: Option<String> = self.conn.clone().get(peer_key(peer_id)).await?;
+        peer.map(|peer| from_json(&peer)).transpose()
+    }
+
+    async fn nodes(&self) -> Result<Vec<NodeId>, StoreError> {
+        // Redis deletes a set with its last member, so only nodes with peers are left.
+        let mut conn: ConnectionManager = self.conn.clone();
+        let keys: Vec<String> = conn.scan_match::<_, String>(node_peers_key("*")).await?.collect().await;
+        Ok(keys.iter().map(|key| key[node_peers_key("").len()..].to_string()).collect())
+    }
+
+    async fn peers_on(&self, node_id: &str) -> Result<Vec<PeerId>, StoreError> {
+        Ok(self.conn.clone().smembers(node_peers_key(node_id)).await?)
+    }
+}
+
+impl From<RedisError> for StoreError {
+    fn from(e: RedisError) -> Self {
+        StoreError::Backend(e.to_string())
+    }
+}
+
+fn room_key(room_id: &str) -> String {
+    format!("{}:room:{}", KEY_PREFIX, room_id)
+}
+
+fn rooms_key() -> String {
+    format!("{}:rooms", KEY_PREFIX)
+}
+
+fn peer_rooms_key(peer_id: &str) -> String {
+    format!("{}:peer_rooms:{}", KEY_PREFIX, peer_id)
+}
+
+fn peer_key(peer_id: &str) -> String {
+    format!("{}:peer:{}", KEY_PREFIX, peer_id)
+}
+
+fn node_peers_key(node_id: &str) -> String {
+    format!("{}:node_peers:{}", KEY_PREFIX, node_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::websocket::store::conformance;
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(room_key("test_room"), "signaling:room:test_room");
+        assert_eq!(peer_rooms_key("peer_a"), format!("{}peer_a", peer_rooms_key("")));
+        assert_eq!(node_peers_key("node_a"), format!("{}node_a", node_peers_key("")));
+    }
+
+    /// Runs against the scratch database in `WS_SERVER_TEST_REDIS_URL`, skipped when unset.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_redis_storage() {
+        let Ok(url) = std::env::var("WS_SERVER_TEST_REDIS_URL") else {
+            return;
+        };
+        conformance::run(Arc::new(RedisStorage::connect(&url).await.unwrap())).await;
+    }
+}
\ No newline at end of file
@@ -0,0 +1,304 @@
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::websocket::data_transfer::Room;
+use crate::websocket::store::{blocking, participant_values, rooms_from_rows, to_json, to_label};
+use crate::websocket::store::{ParticipantRow, PeerRecord, RoomRow, Storage, StoreError, Versioned};
+
+use super::NodeId;
+use super::PeerId;
+use super::RoomId;
+
+/// Schema changes, applied in order and tracked in `PRAGMA user_version`. Append new
+/// versions here; never edit one that has shipped.
+const MIGRATIONS: &[&str] = &[
+    // 1: rooms, their members and connected peers.
+    "CREATE TABLE rooms (
+        room_id TEXT NOT NULL PRIMARY KEY,
+        owner TEXT NULL,
+        policy TEXT NOT NULL,
+        access TEXT NOT NULL,
+        version INTEGER NOT NULL DEFAULT 1
+    );
+    CREATE TABLE participants (
+        room_id TEXT NOT NULL REFERENCES rooms (room_id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        peer_id TEXT NOT NULL,
+        role TEXT NOT NULL,
+        offer TEXT NULL,
+        candidates TEXT NOT NULL,
+        PRIMARY KEY (room_id, position)
+    );
+    CREATE INDEX participants_peer ON participants (peer_id);
+    CREATE TABLE peers (
+        peer_id TEXT NOT NULL PRIMARY KEY,
+        addr TEXT NOT NULL,
+        user_id TEXT NULL
+    );",
+    // 2: the node each peer is connected to.
+    "ALTER TABLE peers ADD COLUMN node_id TEXT NOT NULL DEFAULT '';",
+    // 3: finding the peers of a node that is gone.
+    "CREATE INDEX peers_node ON peers (node_id);",
+];
+
+/// How long a write waits for another process holding the database lock.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Rooms in a local SQLite file. Queries run on the blocking pool over one connection;
+/// several servers may share the file through SQLite's own locking.
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    /// Opens or creates the database at `path` and brings the schema up to date.
+    pub async fn open(path: &str) -> Result<SqliteStorage, StoreError> {
+        let path: String = path.to_string();
+        let conn: Connection = blocking(move || {
+            let mut conn: Connection = Connection::open(path)?;
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            conn.pragma_update(None, "foreign_keys", true)?;
+            migrate(&mut conn)?;
+            Ok(conn)
+        }).await?;
+        Ok(SqliteStorage { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    async fn with_conn<T, F>(&self, query: F) -> Result<T, StoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, StoreError> + Send + 'static,
+    {
+        let conn: Arc<Mutex<Connection>> = self.conn.clone();
+        blocking(move || {
+            let mut conn = conn.lock().map_err(|_| StoreError::Backend(String::from("the connection is poisoned")))?;
+            query(&mut conn)
+        }).await
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn insert_room(&self, room: &Room) -> Result<bool, StoreError> {
+        let room: Room = room.clone();
+        self.with_conn(move |conn| {
+            let tx: Transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let inserted: usize = tx.execute(
+                "INSERT OR IGNORE INTO rooms (room_id, owner, policy, access) VALUES (?1, ?2, ?3, ?4)",
+                params![room.room_id, room.owner, to_label(&room.policy), to_json(&room.access)],
+            )?;
+            if inserted == 0 {
+                return Ok(false);
+            }
+            insert_participants(&tx, &room)?;
+            tx.commit()?;
+            Ok(true)
+        }).await
+    }
+
+    async fn room(&self, room_id: &str) -> Result<Option<Versioned<Room>>, StoreError> {
+        let room_id: RoomId = room_id.to_string();
+        self.with_conn(move |conn| {
+            let row: Option<(RoomRow, u64)> = conn.query_row(
+                "SELECT room_id, owner, policy, access, version FROM rooms WHERE room_id = ?1",
+                params![room_id],
+                |row| Ok(((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?), row.get(4)?)),
+            ).optional()?;
+            let Some((row, version)) = row else {
+                return Ok(None);
+            };
+            let participant_rows: Vec<ParticipantRow> = participant_rows(
+                conn,
+                "SELECT room_id, peer_id, role, offer, candidates FROM participants WHERE room_id = ?1 ORDER BY position",
+                params![room_id],
+            )?;
+            let room: Option<Room> = rooms_from_rows(vec![row], participant_rows)?.pop();
+            Ok(room.map(|value| Versioned { value, version }))
+        }).await
+    }
+
+    async fn replace_room(&self, room: &Room, version: u64) -> Result<bool, StoreError> {
+        let room: Room = room.clone();
+        self.with_conn(move |conn| {
+            let tx: Transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let updated: usize = tx.execute(
+                "UPDATE rooms SET owner = ?1, policy = ?2, access = ?3, version = version + 1 WHERE room_id = ?4 AND version = ?5",
+                params![room.owner, to_label(&room.policy), to_json(&room.access), room.room_id, version],
+            )?;
+            if updated == 0 {
+                return Ok(false);
+            }
+            tx.execute("DELETE FROM participants WHERE room_id = ?1", params![room.room_id])?;
+            insert_participants(&tx, &room)?;
+            tx.commit()?;
+            Ok(true)
+        }).await
+    }
+
+    async fn remove_room(&self, room_id: &str, version: u64) -> Result<bool, StoreError> {
+        let room_id: RoomId = room_id.to_string();
+        self.with_conn(move |conn| {
+            let removed: usize = conn.execute("DELETE FROM rooms WHERE room_id = ?1 AND version = ?2", params![room_id, version])?;
+            Ok(removed > 0)
+        }).await
+    }
+
+    async fn rooms(&self) -> Result<Vec<Room>, StoreError> {
+        self.with_conn(|conn| {
+            let rows: Vec<RoomRow> = conn.prepare("SELECT room_id, owner, policy, access FROM rooms")?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+                .collect::<Result<_, _>>()?;
+            let participant_rows: Vec<ParticipantRow> = participant_rows(
+                conn,
+                "SELECT room_id, peer_id, role, offer, candidates FROM participants ORDER BY room_id, position",
+                [],
+            )?;
+            rooms_from_rows(rows, participant_rows)
+        }).await
+    }
+
+    async fn room_count(&self) -> Result<usize, StoreError> {
+        self.with_conn(|conn| Ok(conn.query_row("SELECT COUNT(*) FROM rooms", [], |row| row.get(0))?)).await
+    }
+
+    async fn rooms_of(&self, peer_id: &str) -> Result<Vec<RoomId>, StoreError> {
+        let peer_id: String = peer_id.to_string();
+        self.with_conn(move |conn| {
+            let room_ids: Vec<RoomId> = conn.prepare("SELECT DISTINCT room_id FROM participants WHERE peer_id = ?1")?
+                .query_map(params![peer_id], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            Ok(room_ids)
+        }).await
+    }
+
+    async fn insert_peer(&self, peer: &PeerRecord) -> Result<(), StoreError> {
+        let peer: PeerRecord = peer.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO peers (peer_id, addr, user_id, node_id) VALUES (?1, ?2, ?3, ?4)",
+                params![peer.peer_id, peer.addr.to_string(), peer.user_id, peer.node_id],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn remove_peer(&self, peer_id: &str) -> Result<bool, StoreError> {
+        let peer_id: String = peer_id.to_string();
+        self.with_conn(move |conn| Ok(conn.execute("DELETE FROM peers WHERE peer_id = ?1", params![peer_id])? > 0)).await
+    }
+
+    async fn peer(&self, peer_id: &str) -> Result<Option<PeerRecord>, StoreError> {
+        let peer_id: String = peer_id.to_string();
+        self.with_conn(move |conn| {
+            let row: Option<(String, String, Option<String>, NodeId)> = conn.query_row(
+                "SELECT peer_id, addr, user_id, node_id FROM peers WHERE peer_id = ?1",
+                params![peer_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            ).optional()?;
+            row.map(|(peer_id, addr, user_id, node_id)| Ok(PeerRecord {
+                peer_id,
+                addr: addr.parse().map_err(|_| StoreError::Corrupt(format!("invalid peer address {:?}", addr)))?,
+                user_id,
+                node_id,
+            })).transpose()
+        }).await
+    }
+
+    async fn nodes(&self) -> Result<Vec<NodeId>, StoreError> {
+        self.with_conn(|conn| {
+            let nodes: Vec<NodeId> = conn.prepare("SELECT DISTINCT node_id FROM peers")?
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            Ok(nodes)
+        }).await
+    }
+
+    async fn peers_on(&self, node_id: &str) -> Result<Vec<PeerId>, StoreError> {
+        let node_id: String = node_id.to_string();
+        self.with_conn(move |conn| {
+            let peer_ids: Vec<PeerId> = conn.prepare("SELECT peer_id FROM peers WHERE node_id = ?1")?
+                .query_map(params![node_id], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            Ok(peer_ids)
+        }).await
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Backend(e.to_string())
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), StoreError> {
+    let tx: Transaction = conn.transaction_with_behavior(TransactionBehavior::Exclusive)?;
+    let applied: usize = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (index, statements) in MIGRATIONS.iter().enumerate().skip(applied) {
+        tx.execute_batch(statements)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn participant_rows<P: rusqlite::Params>(conn: &Connection, sql: &str, params: P) -> Result<Vec<ParticipantRow>, StoreError> {
+    let rows: Vec<ParticipantRow> = conn.prepare(sql)?
+        .query_map(params, |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))?
+        .collect::<Result<_, _>>()?;
+    Ok(rows)
+}
+
+fn insert_participants(tx: &Transaction, room: &Room) -> Result<(), StoreError> {
+    let mut insert = tx.prepare(
+        "INSERT INTO participants (room_id, position, peer_id, role, offer, candidates) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+    for (position, peer_id, role, offer, candidates) in participant_values(room) {
+        insert.execute(params![room.room_id, position, peer_id, role, offer, candidates])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::websocket::data_transfer::RoomPolicy;
+    use crate::websocket::store::conformance;
+
+    fn remove_database(path: &Path) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sqlite_storage() {
+        let path: PathBuf = std::env::temp_dir().join(format!("signaling_{}.db", Uuid::new_v4().simple()));
+        let storage: SqliteStorage = SqliteStorage::open(path.to_str().unwrap()).await.unwrap();
+        conformance::run(Arc::new(storage)).await;
+        remove_database(&path);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_reopen() {
+        let path: PathBuf = std::env::temp_dir().join(format!("signaling_{}.db", Uuid::new_v4().simple()));
+        let room: Room = Room::new(String::from("test_room"), String::from("owner"), RoomPolicy::KeepUntilEmpty);
+        {
+            let storage: SqliteStorage = SqliteStorage::open(path.to_str().unwrap()).await.unwrap();
+            assert!(storage.insert_room(&room).await.unwrap());
+        }
+
+        // Reopening an up-to-date database applies no migration and keeps the rooms.
+        let storage: SqliteStorage = SqliteStorage::open(path.to_str().unwrap()).await.unwrap();
+        let stored: Versioned<Room> = storage.room("test_room").await.unwrap().unwrap();
+        assert_eq!(stored.version, 1);
+        assert_eq!(to_json(&stored.value), to_json(&room));
+        drop(storage);
+        remove_database(&path);
+    }
+}
\ No newline at end of file
@@ -1,57 +1,256 @@
-use crate::websocket::data_transfer::{DataTransfer, find_room};
-use crate::websocket::data_transfer::{DataType, StoreRoom};
+use crate::config::Settings;
+use crate::error::Error;
+use crate::logging::redact_message;
+use crate::metrics::METRICS;
+use crate::websocket::data_transfer::{DataTransfer, deliver};
+use crate::websocket::data_transfer::{DataType, RoomAccess, RoomPolicy, SignalEnvelope, SignalMessage};
+use crate::websocket::keepalive::{Keepalive, Liveness};
+use crate::websocket::resume::Lease;
 
-use futures_util::StreamExt;
-use std::net::SocketAddr;
+use futures_util::{SinkExt, Stream, StreamExt};
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::{select, task::spawn_blocking, time::{sleep_until, Instant}};
+use tokio_tungstenite::tungstenite::{protocol::{frame::coding::CloseCode, CloseFrame, Message}, Error as WsError};
+use tracing::{debug, info_span, warn, Instrument, Span};
 
-use super::ChatRoom;
-use super::ChatRooms;
+use super::PeerId;
 use super::PeerMap;
-use super::StreamRead;
+use super::SharedBus;
+use super::SharedStorage;
+use super::StreamWrite;
 
 pub struct WebRTCStreamTransfer;
 impl WebRTCStreamTransfer {
-    pub async fn response_msg(peers: PeerMap, mut rooms: ChatRooms, mut read: StreamRead, addr: SocketAddr) {
-        while let Some(raw_msg) = read.next().await {
+    /// Reads a peer's frames until its socket closes, pinging it meanwhile. A peer that
+    /// misses the pong deadline or the idle timeout is dropped like one that hung up.
+    /// With a `lease`, a connection lost without a close frame suspends the peer for a
+    /// reconnect instead of closing it.
+    pub async fn response_msg<R>(peers: PeerMap, storage: SharedStorage, bus: SharedBus, mut read: R, peer_id: PeerId, lease: Option<Lease>, settings: Arc<Settings>)
+    where
+        R: Stream<Item = Result<Message, WsError>> + Unpin,
+    {
+        let mut keepalive: Keepalive = Keepalive::new(&settings, Instant::now());
+        loop {
+            let raw_msg = select! {
+                raw_msg = read.next() => match raw_msg {
+                    Some(raw_msg) => raw_msg,
+                    None => {
+                        Self::hang_up(peers, storage, bus, &peer_id, lease.as_ref(), None).await;
+                        return;
+                    },
+                },
+                _ = sleep_until(keepalive.deadline()) => {
+                    match keepalive.poll(Instant::now()) {
+                        Some(Liveness::Ping) => {
+                            let Some(tx) = peers.read().await.get(&peer_id).map(|peer| peer.tx.clone()) else {
+                                return;
+                            };
+                            // A peer whose queue is full or closed misses the pong and is dropped then.
+                            // After a resume this pings the new connection, which does no harm.
+                            let _ = tx.try_send(Message::Ping(Vec::new()));
+                        },
+                        Some(Liveness::Expired(reason)) => {
+                            warn!(reason, "dropping an unresponsive peer");
+                            METRICS.keepalive_timeouts.inc(reason);
+                            // Tells the peer why in case it is still listening.
+                            let farewell: Message = Message::Close(Some(CloseFrame {
+                                code: CloseCode::Away,
+                                reason: reason.into(),
+                            }));
+                            Self::hang_up(peers, storage, bus, &peer_id, lease.as_ref(), Some(farewell)).await;
+                            return;
+                        },
+                        None => {},
+                    }
+                    continue;
+                },
+            };
             match raw_msg {
                 Ok(msg) => { 
-                    println!("client message from [{}]: {}", addr, msg);    
-                    let msg_string: String = msg.to_string();                    
-                    let raw_data: Result<StoreRoom, _> = serde_json::from_str(&msg_string);
-                    match raw_data {
-                        Ok(data) => {
-                            if msg.is_text() || msg.is_binary() { 
-                                let room: Option<ChatRoom> = find_room(&mut rooms, data.room_id.clone()).await;
-                                match data.data_type.as_str() {
-                                    "store_room" => DataType::store_room(rooms.clone(), room, data, addr).await,
-                                    "store_offer" => DataType::store_offer(rooms.clone(), room, data).await,
-                                    "store_candidate" => DataType::store_candidate(rooms.clone(), room, data).await,
-                                    "send_answer" => DataType::send_answer(room, data, peers.clone()).await,
-                                    "send_candidate" => DataType::send_candidate(room, data, peers.clone()).await,
-                                    "join_call" => DataType::join_call(room, peers.clone()).await,
-                                    _ => eprintln!("Data type is incorrect!"),
-                                }
-                            }
-                        },
-                        Err(err_msg) => eprintln!("store room data type error: {}", err_msg)
+                    keepalive.on_frame();
+                    if msg.is_text() || msg.is_binary() { 
+                        keepalive.on_message(Instant::now());
+                        let msg_string: String = msg.to_string();                    
+                        debug!(payload = %redact_message(&msg_string), "client message");
+                        Self::handle_msg(peers.clone(), storage.clone(), bus.clone(), &peer_id, &settings, &msg_string).await;
                     }
 
                     if msg.is_close() {
-                        DataType::close(rooms.clone(), peers.clone(), addr).await;
+                        // A close frame means the peer left on purpose, so it is not kept for a reconnect.
+                        let current: bool = match &lease {
+                            Some(lease) => lease.release().await,
+                            None => true,
+                        };
+                        if current {
+                            DataType::close(storage.clone(), peers.clone(), bus.clone(), peer_id.clone()).await;
+                        }
                     }
                 }
                 Err(e) => {
-                    DataType::close(rooms.clone(), peers.clone(), addr).await;
-                    eprintln!("an error occured while processing incoming messages: {}", e);
-                    continue;
+                    warn!(error = %e, "an error occured while processing incoming messages");
+                    Self::hang_up(peers, storage, bus, &peer_id, lease.as_ref(), None).await;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Ends a connection that went away without a close frame, queueing `farewell` on it
+    /// first. A peer with a lease is suspended, any other one closed.
+    async fn hang_up(peers: PeerMap, storage: SharedStorage, bus: SharedBus, peer_id: &PeerId, lease: Option<&Lease>, farewell: Option<Message>) {
+        let tx: Option<Sender<Message>> = match lease {
+            Some(lease) => lease.suspend(&peers, &storage, &bus).await,
+            None => peers.read().await.get(peer_id).map(|peer| peer.tx.clone()),
+        };
+        if let (Some(tx), Some(farewell)) = (tx, farewell) {
+            let _ = tx.try_send(farewell);
+        }
+        if lease.is_none() {
+            DataType::close(storage, peers, bus, peer_id.clone()).await;
+        }
+    }
+
+    /// Runs one inbound signaling message and queues its `ack` or `error` reply on the
+    /// sender's outbound channel. Shared by every transport.
+    pub async fn handle_msg(peers: PeerMap, storage: SharedStorage, bus: SharedBus, peer_id: &PeerId, settings: &Settings, msg_string: &str) {
+        let (request_id, raw_data): (Option<String>, Result<SignalMessage, Error>) = Self::parse_msg(msg_string);
+        let reply: Option<Value> = match raw_data {
+            Ok(data) => {
+                METRICS.messages_in.inc(data.data_type());
+                let ack: Option<Value> = request_id.clone().map(|request_id| data.ack(request_id));
+                let room_span: Span = info_span!("room", room_id = %data.room_id(), data_type = data.data_type());
+                match Self::dispatch(peers.clone(), storage.clone(), bus.clone(), data, peer_id.clone(), settings.room_policy).instrument(room_span).await {
+                    Ok(()) => ack,
+                    Err(error) => Some(Self::error_reply(peer_id, error, request_id)),
                 }
+            },
+            Err(error) => {
+                METRICS.parse_errors.inc();
+                Some(Self::error_reply(peer_id, error, request_id))
+            },
+        };
+        if let Some(reply) = reply {
+            let reply_string: String = serde_json::to_string(&reply).expect("Failed to serialize!");
+            deliver(storage, peers, bus, std::slice::from_ref(peer_id), Message::Text(reply_string)).await;
+        }
+    }
+
+    /// Drains a peer's outbound queue onto its socket. Runs until the queue is dropped
+    /// or the socket stops accepting writes.
+    pub async fn send_msg(mut write: StreamWrite, mut rx: Receiver<Message>, peer_id: PeerId) {
+        while let Some(msg) = rx.recv().await {
+            if let Err(e) = write.send(msg).await {
+                warn!(peer_id = %peer_id, error = %e, "failed to write outgoing message");
+                METRICS.send_failures.inc("write_error");
+                return;
             }
         }
+
+        // The peer was dropped from the registry, close the socket so its read loop ends too.
+        if let Err(e) = write.close().await {
+            warn!(peer_id = %peer_id, error = %e, "failed to close the connection");
+        }
+    }
+
+    /// Parses an inbound frame, keeping hold of its `request_id` even when the rest of
+    /// the message is malformed so the error reply can still be correlated.
+    pub fn parse_msg(msg_string: &str) -> (Option<String>, Result<SignalMessage, Error>) {
+        let value: Value = match serde_json::from_str(msg_string) {
+            Ok(value) => value,
+            Err(err_msg) => return (None, Err(Error::InvalidMessage(err_msg.to_string()))),
+        };
+        let request_id: Option<String> = value.get("request_id").and_then(Value::as_str).map(String::from);
+        match serde_json::from_value::<SignalEnvelope>(value) {
+            Ok(envelope) => (envelope.request_id, Ok(envelope.message)),
+            Err(err_msg) => (request_id, Err(Error::InvalidMessage(err_msg.to_string()))),
+        }
+    }
+
+    fn error_reply(peer_id: &PeerId, error: Error, request_id: Option<String>) -> Value {
+        warn!(peer_id = %peer_id, code = error.code(), "{}", error);
+        error.reply(request_id)
+    }
+
+    async fn dispatch(peers: PeerMap, storage: SharedStorage, bus: SharedBus, data: SignalMessage, peer_id: PeerId, room_policy: RoomPolicy) -> Result<(), Error> {
+        match data {
+            SignalMessage::StoreRoom { room_id, password, invite_only, default_role } => {
+                // Hashing the password is slow on purpose, so keep it off the async workers.
+                let access: RoomAccess = spawn_blocking(move || RoomAccess::new(password.as_deref(), invite_only, default_role)).await
+                    .expect("Failed to hash the room password!");
+                DataType::store_room(storage, room_id, peer_id, room_policy, access).await
+            },
+            SignalMessage::StoreOffer { room_id, offer } => DataType::store_offer(storage, room_id, offer, peer_id).await,
+            SignalMessage::StoreCandidate { room_id, candidate } => DataType::store_candidate(storage, room_id, candidate, peer_id).await,
+            SignalMessage::SendOffer { room_id, offer, to } => DataType::send_offer(storage, room_id, offer, to, peers, bus, peer_id).await,
+            SignalMessage::SendAnswer { room_id, answer, to } => DataType::send_answer(storage, room_id, answer, to, peers, bus, peer_id).await,
+            SignalMessage::SendCandidate { room_id, candidate, to } => DataType::send_candidate(storage, room_id, candidate, to, peers, bus, peer_id).await,
+            SignalMessage::JoinCall { room_id, password, invite_token } => DataType::join_call(storage, room_id, peers, bus, peer_id, password, invite_token).await,
+            SignalMessage::CreateInvite { room_id, role } => DataType::create_invite(storage, room_id, role, peers, bus, peer_id).await,
+            SignalMessage::Kick { room_id, peer_id: target } => DataType::kick(storage, room_id, target, peers, bus, peer_id).await,
+            SignalMessage::SetRole { room_id, peer_id: target, role } => DataType::set_role(storage, room_id, target, role, peers, bus, peer_id).await,
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use futures_util::stream;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tokio::sync::{mpsc::channel, Mutex, RwLock};
+
+    use super::*;
+    use crate::websocket::bus::local::LocalHub;
+    use crate::websocket::data_transfer::{register, Room};
+    use crate::websocket::resume::suspended;
+    use crate::websocket::store::memory::MemoryStorage;
+    use crate::websocket::{Peer, Resumptions, OUTBOUND_QUEUE_SIZE};
+
+    async fn connect(storage: &SharedStorage, peers: &PeerMap, bus: &SharedBus, peer_id: &str) -> Receiver<Message> {
+        let (tx, rx): (Sender<Message>, Receiver<Message>) = channel(OUTBOUND_QUEUE_SIZE);
+        let peer: Peer = Peer { addr: "127.0.0.1:8080".parse().unwrap(), tx, identity: None };
+        register(storage, peers, bus, peer_id.to_string(), peer).await.unwrap();
+        rx
+    }
+
+    #[tokio::test]
+    async fn test_webrtc_response_msg() {
+        let storage: SharedStorage = Arc::new(MemoryStorage::new());
+        let peers: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let bus: SharedBus = Arc::new(LocalHub::new().join(String::from("node_a")));
+        let resumptions: Resumptions = Arc::new(Mutex::new(HashMap::new()));
+        let settings: Arc<Settings> = Arc::new(Settings::default());
+
+        // A reader that ends without a close frame hangs up a peer without a lease...
+        let _rx = connect(&storage, &peers, &bus, "peer_1").await;
+        assert!(storage.insert_room(&Room::new(String::from("test_room"), String::from("peer_1"), RoomPolicy::default())).await.unwrap());
+        WebRTCStreamTransfer::response_msg(peers.clone(), storage.clone(), bus.clone(), stream::empty(), String::from("peer_1"), None, settings.clone()).await;
+        assert!(peers.read().await.is_empty());
+        assert_eq!(storage.peer("peer_1").await.unwrap(), None);
+        assert_eq!(storage.room_count().await.unwrap(), 0);
+
+        // ...and suspends one with a lease.
+        let _rx = connect(&storage, &peers, &bus, "peer_2").await;
+        let lease: Lease = Lease::issue(&resumptions, String::from("peer_2"), Duration::from_secs(60)).await;
+        WebRTCStreamTransfer::response_msg(peers.clone(), storage.clone(), bus.clone(), stream::empty(), String::from("peer_2"), Some(lease), settings).await;
+        assert_eq!(suspended(&resumptions).await, 1);
+        assert!(peers.read().await.contains_key("peer_2"));
+    }
+
     #[test]
-    fn test_webrtc_response_msg() {}
+    fn test_parse_msg() {
+        let (request_id, data) = WebRTCStreamTransfer::parse_msg(r#"{"data_type":"join_call","room_id":"test_room","request_id":"req-1"}"#);
+        assert_eq!(request_id, Some(String::from("req-1")));
+        assert!(matches!(data, Ok(SignalMessage::JoinCall { .. })));
+
+        let (request_id, data) = WebRTCStreamTransfer::parse_msg(r#"{"data_type":"bogus","room_id":"test_room","request_id":"req-2"}"#);
+        assert_eq!(request_id, Some(String::from("req-2")));
+        assert!(matches!(data, Err(Error::InvalidMessage(_))));
+
+        let (request_id, data) = WebRTCStreamTransfer::parse_msg("not json");
+        assert_eq!(request_id, None);
+        assert!(matches!(data, Err(Error::InvalidMessage(_))));
+    }
 }
\ No newline at end of file